@@ -1,11 +1,13 @@
 pub mod libs;
 pub mod structs;
 
-use log::{info, warn};
+use log::{error, info, warn};
 
 use libs::variable::get_environment_variable;
 use libs::version::print_version;
 use libs::telegram::BotCommandService;
+use libs::shutdown::Shutdown;
+use libs::redis::RedisConnections;
 use teloxide::Bot;
 
 #[tokio::main]
@@ -16,15 +18,21 @@ async fn main() {
     info!("Starting bot commands service");
 
     let redis_url = get_environment_variable("REDIS_URL");
+    let shutdown = Shutdown::listen();
+
+    match RedisConnections::connect(&redis_url).await {
+        Ok(connections) => {
+            // Commands read and write subscriber records, those have to be up to date
+            if let Err(e) = connections.subscribers.migrate(&connections.config).await {
+                error!("Migrating the subscribers failed {:?}", e);
+            }
 
-    match redis::Client::open(redis_url.clone()) {
-        Ok(redis_client) => {
             let bot_service = BotCommandService {
                 bot: Bot::from_env(),
-                redis_client,
+                connections,
             };
 
-            let _ = bot_service.start().await;
+            let _ = bot_service.start(shutdown).await;
         }
         Err(_) => warn!("Could not connect to redis"),
     }
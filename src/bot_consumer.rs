@@ -3,22 +3,26 @@ pub mod structs;
 
 use libs::variable::get_environment_variable;
 use libs::version::print_version;
-use libs::redis::Database;
-use libs::telegram::BotMessageService;
+use libs::redis::RedisConnections;
+use libs::telegram::{BotError, BotMessageService};
 use log::{error, info};
-use redis::ConnectionLike;
+use std::time::Duration;
 use thiserror::Error;
+use tokio::time::Instant;
 
 use teloxide::Bot;
 
-use regex::Regex;
-
-use crate::libs::redis::{get_config, get_subscribers, increase_config_value, create_generic_config, read_message};
+use crate::libs::deal_store::DealStore;
+use crate::libs::redis::get_timestamp;
+use crate::libs::digest::DIGEST_CHECK_SECONDS;
+use crate::libs::shutdown::Shutdown;
+use crate::libs::stream::{get_claim_refresh_interval, get_consumer_name};
+use crate::structs::message::{DeadLetter, Message};
 
 #[derive(Debug, Error)]
 enum ConsumerError {
     #[error(transparent)]
-    RedisError(#[from] redis::RedisError),
+    RedisError(#[from] libs::redis::RedisError),
 }
 
 #[tokio::main]
@@ -29,112 +33,304 @@ async fn main() -> Result<(), ConsumerError> {
     info!("Starting bot consumer service");
 
     let redis_url = get_environment_variable("REDIS_URL");
-    let sanitize_regex = Regex::new(r"([^\w\s\\'\\’\\$\\€])").unwrap();
+    let mut shutdown = Shutdown::listen();
+    let consumer_name = get_consumer_name();
+    let claim_refresh_interval = get_claim_refresh_interval();
 
-    match redis::Client::open(redis_url.clone()) {
-        Ok(redis_client) => {
+    match RedisConnections::connect(&redis_url).await {
+        Ok(connections) => {
             // Make sure we have the required configuration available
-            let _ = create_generic_config(redis_client.clone());
+            let _ = connections.config.create_defaults().await;
 
-            let bot_service = BotMessageService {
-                bot: Bot::from_env(),
-            };
+            if let Err(e) = connections.deals.create_message_stream().await {
+                error!("Creating the message stream failed {:?}", e);
+            }
 
-            loop {
-                if redis_client.is_open() == false {
-                    panic!("Redis connection dropped");
-                }
+            if let Err(e) = connections.subscribers.migrate(&connections.config).await {
+                error!("Migrating the subscribers failed {:?}", e);
+            }
 
-                if let Ok(mut con) = redis_client.get_connection() {
-                    if let Some(message) = read_message(&mut con) {
-                        info!("{}", message.id);
+            // Reading the stream blocks its connection, so it gets a store of its own
+            let stream = DealStore::connect(&redis_url).await?;
 
-                        // Make sure we're using the message database
-                        let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-                            .arg(Database::MESSAGE as u8)
-                            .query(&mut con);
+            info!("Consuming as {}", consumer_name);
 
-                        let res: i64 = redis::cmd("EXISTS").arg(&message.id).query(&mut con)?;
+            let bot_service =
+                BotMessageService::new(Bot::from_env(), connections.config.clone());
+            let mut last_digest_check: Option<Instant> = None;
 
-                        // Only send if the message has not been send yet
-                        if res == 1 {
-                            continue;
+            while !shutdown.is_triggered() {
+                match connections.is_available().await {
+                    true => {
+                        if last_digest_check
+                            .is_none_or(|c| c.elapsed().as_secs() >= DIGEST_CHECK_SECONDS)
+                        {
+                            last_digest_check = Some(Instant::now());
+                            send_due_digests(&connections, &bot_service).await;
                         }
 
-                        // Store this message in Redis to make sure it doesn't get
-                        // queued again
-                        let _: Result<(), redis::RedisError> =
-                            redis::cmd("SET").arg(&message.id).arg(1).query(&mut con);
-
-                        // Set expiration for key - 2 days
-                        let _: Result<(), redis::RedisError> = redis::cmd("EXPIRE")
-                            .arg(&message.id)
-                            .arg(172800)
-                            .query(&mut con);
-
-                        // Check if the bot has been disabled by the admin
-                        let is_operational: String = get_config(
-                            &mut con,
-                            libs::redis::Config::OperationalKey,
-                            Database::MESSAGE,
-                        )
-                        .unwrap_or("1".to_string());
-
-                        // Only send messages and get subs when we're operational
-                        if is_operational.eq(&"1") {
-                            let _ = increase_config_value::<()>(
-                                &mut con,
-                                libs::redis::Config::DealsSentKey,
-                                Database::MESSAGE,
-                                1,
-                            );
-
-                            info!("Sending message {:?}", &message);
-
-                            let subscribers = get_subscribers(redis_client.clone()).await;
-                            if let Ok(subs) = subscribers {
-                                let mut messages_sent = 0;
-
-                                for (chat_id, categories) in subs {
-                                    // If user did not subscribe for this category, bail
-                                    if let Some(c) = categories {
-                                        if c.contains(&message.payload.category) == false {
+                        // Don't block forever, so a shutdown signal gets noticed in time
+                        if let Some((stream_id, mut message)) =
+                            stream.read_message(&consumer_name, 5).await
+                        {
+                            info!("{}", message.id);
+
+                            // Checkpointed messages were already stored, only the remaining
+                            // recipients still have to receive them
+                            let is_checkpoint = message.recipients.is_some();
+
+                            // Only send if the message has not been send yet. Claiming it
+                            // stores the stream id, so it doesn't get queued again
+                            if !is_checkpoint {
+                                match connections
+                                    .deals
+                                    .claim_message(&message.id, &stream_id)
+                                    .await
+                                {
+                                    Ok(true) => (),
+                                    Ok(false) => {
+                                        let _ =
+                                            connections.deals.acknowledge_message(&stream_id).await;
+                                        continue;
+                                    }
+                                    // Left unacknowledged, so it gets claimed again later
+                                    Err(e) => {
+                                        error!("Claiming {} failed {:?}", message.id, e);
+                                        continue;
+                                    }
+                                }
+                            }
+
+                            // Only send messages and get subs when we're operational, the
+                            // admin can disable the bot
+                            if connections.config.is_operational().await {
+                                if !is_checkpoint {
+                                    let _ = connections
+                                        .config
+                                        .increase(libs::redis::Config::DealsSentKey, 1)
+                                        .await;
+                                }
+
+                                info!("Sending message {:?}", &message);
+
+                                let subscribers =
+                                    connections.subscribers.get_interested(&message).await;
+                                if let Ok(subs) = subscribers {
+                                    let mut messages_sent: u64 = 0;
+                                    let mut remaining: Vec<String> = vec![];
+                                    let mut last_claimed = Instant::now();
+
+                                    for (chat_id, subscriber) in subs {
+                                        // Large audiences take longer than the claim idle time,
+                                        // don't let another consumer send this deal again
+                                        if last_claimed.elapsed() >= claim_refresh_interval {
+                                            last_claimed = Instant::now();
+
+                                            if let Err(e) = connections
+                                                .deals
+                                                .keep_claimed(&consumer_name, &stream_id)
+                                                .await
+                                            {
+                                                error!(
+                                                    "Renewing claim on {} failed {:?}",
+                                                    message.id, e
+                                                );
+                                            }
+                                        }
+
+                                        match &message.recipients {
+                                            Some(recipients) => {
+                                                if !recipients.contains(&chat_id) {
+                                                    continue;
+                                                }
+                                            }
+                                            None => {
+                                                // If user did not subscribe for this feed, category or keyword, bail
+                                                if !subscriber.wants(&message) {
+                                                    continue;
+                                                }
+                                            }
+                                        }
+
+                                        // Out of time, leave this recipient for the next consumer
+                                        if shutdown.is_overdue() {
+                                            remaining.push(chat_id);
+                                            continue;
+                                        }
+
+                                        // Digest subscribers get their deals bundled later on, during quiet
+                                        // hours deals wait until the subscriber is awake
+                                        if let Some(due) = subscriber.delivery_due(get_timestamp())
+                                        {
+                                            if let Err(e) = connections
+                                                .deals
+                                                .add_to_digest(&chat_id, &message, due)
+                                                .await
+                                            {
+                                                error!(
+                                                    "Holding {} for {} failed {:?}",
+                                                    message.id, chat_id, e
+                                                );
+                                            }
+
                                             continue;
                                         }
-                                    }
 
-                                    let sanitized_title = sanitize_regex
-                                        .replace_all(message.payload.title.as_str(), "\\$1");
+                                        info!("Sending {} to {}", message.payload.link, chat_id);
 
-                                    info!("Sending {} to {}", message.payload.link, chat_id);
+                                        match bot_service.send_deal(chat_id.clone(), &message).await
+                                        {
+                                            Ok(_) => messages_sent += 1,
+                                            Err(e) => {
+                                                if handle_failed_send(
+                                                    &connections,
+                                                    &bot_service,
+                                                    chat_id,
+                                                    std::slice::from_ref(&message),
+                                                    e,
+                                                )
+                                                .await
+                                                {
+                                                    messages_sent += 1;
+                                                }
+                                            }
+                                        }
+                                    }
 
-                                    let _ = bot_service
-                                        .send_message(
-                                            chat_id,
-                                            format!(
-                                                "[{}]({})",
-                                                sanitized_title, message.payload.link
-                                            ),
-                                        )
+                                    let _ = connections
+                                        .config
+                                        .increase(libs::redis::Config::MessagesSentKey, messages_sent)
                                         .await;
 
-                                    messages_sent += 1;
+                                    if !remaining.is_empty() {
+                                        info!(
+                                            "Checkpointing {} with {} remaining recipients",
+                                            message.id,
+                                            remaining.len()
+                                        );
+
+                                        message.recipients = Some(remaining);
+                                        if let Err(e) =
+                                            connections.deals.requeue_message(&message).await
+                                        {
+                                            // Leave it unacknowledged, so it gets claimed again
+                                            error!("Checkpointing {} failed {:?}", message.id, e);
+                                            continue;
+                                        }
+                                    }
                                 }
+                            }
 
-                                let _ = increase_config_value::<()>(
-                                    &mut con,
-                                    libs::redis::Config::MessagesSentKey,
-                                    Database::MESSAGE,
-                                    messages_sent,
-                                );
+                            if let Err(e) =
+                                connections.deals.acknowledge_message(&stream_id).await
+                            {
+                                error!("Acknowledging {} failed {:?}", message.id, e);
                             }
                         }
                     }
+                    false => {
+                        error!("Redis connection failed");
+                        shutdown.sleep(Duration::from_secs(1)).await;
+                    }
                 }
             }
+
+            info!("Stopped bot consumer service");
         }
         Err(_) => error!("Connection with Redis failed"),
     }
 
     Ok(())
 }
+
+async fn send_due_digests(connections: &RedisConnections, bot_service: &BotMessageService) {
+    // Digests wait in their buffer while the bot is stopped
+    if !connections.config.is_operational().await {
+        return;
+    }
+
+    for (chat_id, messages) in connections.deals.take_due_digests().await {
+        info!("Sending digest of {} deals to {}", messages.len(), chat_id);
+
+        let sent = match bot_service.send_digest(chat_id.clone(), &messages).await {
+            Ok(_) => true,
+            Err(e) => handle_failed_send(connections, bot_service, chat_id, &messages, e).await,
+        };
+
+        if sent {
+            let _ = connections
+                .config
+                .increase(libs::redis::Config::MessagesSentKey, 1)
+                .await;
+        }
+    }
+}
+
+// Groups upgraded to a supergroup keep their subscription and get the messages there, chats
+// that are gone are unsubscribed and anything else ends up on the dead-letter list. True when
+// the messages reached the migrated chat after all, a failure there is handled like any other
+async fn handle_failed_send(
+    connections: &RedisConnections,
+    bot_service: &BotMessageService,
+    mut chat_id: String,
+    messages: &[Message],
+    mut e: BotError,
+) -> bool {
+    if let Some(new_chat_id) = e.migrated_to() {
+        info!("Chat {} migrated to {}", chat_id, new_chat_id);
+
+        if let Err(e) = connections.subscribers.move_to(&chat_id, &new_chat_id).await {
+            error!("Moving subscriber {} failed {:?}", chat_id, e);
+        }
+
+        let _ = connections
+            .config
+            .increase(libs::redis::Config::MigratedKey, 1)
+            .await;
+
+        match bot_service.send_digest(new_chat_id.clone(), messages).await {
+            Ok(_) => return true,
+            Err(resend_error) => {
+                chat_id = new_chat_id;
+                e = resend_error;
+            }
+        }
+    }
+
+    // Nothing will ever reach this chat again, stop trying
+    if e.is_chat_gone() {
+        info!("Unsubscribing {}, {}", chat_id, e);
+
+        if let Err(e) = connections.subscribers.remove(&chat_id).await {
+            error!("Unsubscribing {} failed {:?}", chat_id, e);
+        }
+
+        if let Err(e) = connections.deals.drop_digest(&chat_id).await {
+            error!("Dropping the digest of {} failed {:?}", chat_id, e);
+        }
+
+        let _ = connections
+            .config
+            .increase(libs::redis::Config::UnsubscribedKey, 1)
+            .await;
+
+        return false;
+    }
+
+    for message in messages {
+        error!("Sending {} to {} failed {}", message.id, chat_id, e);
+
+        let dead_letter = DeadLetter {
+            chat_id: chat_id.clone(),
+            message: message.clone(),
+            error: e.to_string(),
+            failed_at: get_timestamp(),
+        };
+
+        if let Err(e) = connections.deals.add_dead_letter(&dead_letter).await {
+            error!("Adding dead letter failed {:?}", e);
+        }
+    }
+
+    false
+}
@@ -1,5 +1,8 @@
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
+use std::cmp::Reverse;
+
+use crate::libs::redis::get_timestamp;
 
 pub const CATEGORIES: [&str; 14] = [
     "Elektronica",
@@ -18,20 +21,199 @@ pub const CATEGORIES: [&str; 14] = [
     "Services & Contracten",
 ];
 
-pub fn match_category(category: &str) -> Option<String> {
-    if category.eq("") {
-        return None;
+// Discovered categories that didn't get a deal for this long were most likely renamed
+static STALE_SECONDS: u64 = 2592000;
+
+// Every instance seeds its categories in the same order, so an alias points at the
+// position of its category in that seed. Instances without that category ignore it
+static ALIASES: [(&str, usize); 34] = [
+    ("electronics", 0),
+    ("tech", 0),
+    ("games", 1),
+    ("videogames", 1),
+    ("groceries", 2),
+    ("food", 2),
+    ("supermarket", 2),
+    ("fashion", 3),
+    ("clothing", 3),
+    ("clothes", 3),
+    ("health", 4),
+    ("beauty", 4),
+    ("family", 5),
+    ("kids", 5),
+    ("children", 5),
+    ("baby", 5),
+    ("home", 6),
+    ("house", 6),
+    ("garden", 7),
+    ("diy", 7),
+    ("car", 8),
+    ("cars", 8),
+    ("motorcycle", 8),
+    ("culture", 9),
+    ("leisure", 9),
+    ("sports", 10),
+    ("outdoor", 10),
+    ("phone", 11),
+    ("internet", 11),
+    ("finance", 12),
+    ("insurance", 12),
+    ("services", 13),
+    ("contracts", 13),
+    ("travel", 14),
+];
+
+// Fuzzy matches scoring lower than this are too far off to be what the user meant, a
+// single letter scores around 30
+static MIN_SCORE: i64 = 40;
+
+// The runner-up has to score below this share of the best match, percentage
+static AMBIGUITY_PERCENTAGE: i64 = 80;
+
+// A category name as Pepper put it on a deal, recorded by the message queuing service
+#[derive(Debug, Clone)]
+pub struct SeenCategory {
+    pub name: String,
+    pub deals: u64,
+    pub last_seen: u64,
+}
+
+// The seed list of the instance followed by the categories Pepper added since, busiest
+// first
+pub fn get_live_categories(seed: &[&str], seen: &[SeenCategory]) -> Vec<String> {
+    let mut discovered: Vec<&SeenCategory> = seen
+        .iter()
+        .filter(|c| get_timestamp().saturating_sub(c.last_seen) < STALE_SECONDS)
+        .filter(|c| {
+            !seed
+                .iter()
+                .any(|s| s.to_lowercase().eq(&c.name.to_lowercase()))
+        })
+        .collect();
+
+    discovered.sort_by_key(|c| Reverse(c.deals));
+
+    seed.iter()
+        .map(|c| c.to_string())
+        .chain(discovered.into_iter().map(|c| c.name.clone()))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CategoryMatch {
+    // The lowercase category, the way deals and subscribers store it
+    Found(String),
+    // The categories that scored about the same, to let the user pick one
+    Ambiguous(Vec<String>),
+    NotFound,
+}
+
+// Matches against all `categories`, aliases only resolve to the instance's `seed`
+pub fn match_category(category: &str, seed: &[&str], categories: &[&str]) -> CategoryMatch {
+    let category = category.trim().to_lowercase();
+
+    if category.is_empty() {
+        return CategoryMatch::NotFound;
+    }
+
+    if let Some(exact) = categories.iter().find(|c| c.to_lowercase().eq(&category)) {
+        return CategoryMatch::Found(exact.to_lowercase());
+    }
+
+    let alias = ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq(&category))
+        .and_then(|(_, index)| seed.get(*index));
+
+    if let Some(alias) = alias {
+        return CategoryMatch::Found(alias.to_lowercase());
     }
 
     let matcher = SkimMatcherV2::default();
-    let mut category_match: Option<String> = None;
+    let mut scores: Vec<(i64, &str)> = categories
+        .iter()
+        .filter_map(|c| matcher.fuzzy_match(c, &category).map(|score| (score, *c)))
+        .filter(|(score, _)| *score >= MIN_SCORE)
+        .collect();
+
+    scores.sort_by_key(|(score, _)| Reverse(*score));
+
+    let best = match scores.first() {
+        Some((score, _)) => *score,
+        None => return CategoryMatch::NotFound,
+    };
+
+    let close: Vec<String> = scores
+        .iter()
+        .filter(|(score, _)| score * 100 >= best * AMBIGUITY_PERCENTAGE)
+        .map(|(_, c)| c.to_string())
+        .collect();
+
+    match close.len() {
+        1 => CategoryMatch::Found(close[0].to_lowercase()),
+        _ => CategoryMatch::Ambiguous(close),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nl_match(category: &str) -> CategoryMatch {
+        match_category(category, &CATEGORIES, &CATEGORIES)
+    }
 
-    for pepper_category in CATEGORIES {
-        if matcher.fuzzy_match(pepper_category, category).is_some() {
-            category_match = Some(pepper_category.to_lowercase());
-            break;
-        }
+    #[test]
+    fn match_category_prefers_exact_and_aliases() {
+        assert_eq!(
+            nl_match(" Gaming "),
+            CategoryMatch::Found("gaming".to_string())
+        );
+        assert_eq!(
+            nl_match("clothing"),
+            CategoryMatch::Found("mode & accessoires".to_string())
+        );
     }
 
-    category_match
+    #[test]
+    fn match_category_scores_fuzzy_queries() {
+        assert_eq!(
+            nl_match("auto"),
+            CategoryMatch::Found("auto & motor".to_string())
+        );
+        assert_eq!(
+            nl_match("elektro"),
+            CategoryMatch::Found("elektronica".to_string())
+        );
+        assert_eq!(nl_match("e"), CategoryMatch::NotFound);
+        assert_eq!(nl_match(""), CategoryMatch::NotFound);
+        assert_eq!(
+            nl_match("ge"),
+            CategoryMatch::Ambiguous(vec![
+                "Geldzaken & Verzekeringen".to_string(),
+                "Beauty & Gezondheid".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn aliases_resolve_against_the_seed() {
+        let de = crate::libs::instance::get_instance("de").unwrap();
+        assert_eq!(
+            match_category("travel", de.categories, de.categories),
+            CategoryMatch::Found("reisen".to_string())
+        );
+
+        // The Dutch seed has no travel category, a discovered one must not take its place
+        let mut live = CATEGORIES.to_vec();
+        live.push("Kleding");
+        assert_eq!(
+            match_category("travel", &CATEGORIES, &live),
+            CategoryMatch::NotFound
+        );
+        assert_eq!(
+            match_category("kleding", &CATEGORIES, &live),
+            CategoryMatch::Found("kleding".to_string())
+        );
+    }
 }
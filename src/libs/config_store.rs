@@ -0,0 +1,229 @@
+use redis::aio::ConnectionManager;
+use redis::{FromRedisValue, Script, ToRedisArgs};
+use std::time::Duration;
+
+use crate::libs::redis::{connect_database, get_timestamp, Config, Database, RedisError};
+
+// Token bucket shared by every consumer, timed by the Redis server so their clocks don't
+// matter. ARGV holds the capacity and the tokens added per second, returns the milliseconds
+// to wait before a token can be taken
+static TAKE_TOKEN_SCRIPT: &str = r"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local capacity = tonumber(ARGV[1])
+local per_millisecond = tonumber(ARGV[2]) / 1000
+
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at', 'paused_until')
+local tokens = tonumber(bucket[1]) or capacity
+local updated_at = tonumber(bucket[2]) or now
+local paused_until = tonumber(bucket[3]) or 0
+
+tokens = math.min(capacity, tokens + math.max(0, now - updated_at) * per_millisecond)
+
+local wait = 0
+if paused_until > now then
+    wait = paused_until - now
+elseif tokens < 1 then
+    wait = math.ceil((1 - tokens) / per_millisecond)
+else
+    tokens = tokens - 1
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', now)
+redis.call('PEXPIRE', KEYS[1], math.max(0, paused_until - now) + 60000)
+
+return wait
+";
+
+// Empties the bucket for ARGV[1] milliseconds, a shorter pause never cuts a longer one short
+static PAUSE_BUCKET_SCRIPT: &str = r"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local paused_until = now + tonumber(ARGV[1])
+
+if paused_until > (tonumber(redis.call('HGET', KEYS[1], 'paused_until')) or 0) then
+    redis.call('HSET', KEYS[1], 'tokens', 0, 'updated_at', now, 'paused_until', paused_until)
+    redis.call('PEXPIRE', KEYS[1], paused_until - now + 60000)
+end
+
+return 0
+";
+
+#[derive(Clone)]
+pub struct ConfigStore {
+    con: ConnectionManager,
+}
+
+impl ConfigStore {
+    pub async fn connect(redis_url: &str) -> Result<ConfigStore, RedisError> {
+        Ok(ConfigStore {
+            con: connect_database(redis_url, Database::CONFIG).await?,
+        })
+    }
+
+    pub async fn ping(&self) -> bool {
+        let mut con = self.con.clone();
+
+        redis::cmd("PING")
+            .query_async::<_, String>(&mut con)
+            .await
+            .is_ok()
+    }
+
+    pub async fn create_defaults(&self) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
+            .arg(Config::OperationalKey.value())
+            .arg(1)
+            .query_async::<_, u8>(&mut con)
+            .await;
+
+        let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
+            .arg(Config::MessagesSentKey.value())
+            .arg(0)
+            .query_async::<_, u8>(&mut con)
+            .await;
+
+        let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
+            .arg(Config::DealsSentKey.value())
+            .arg(0)
+            .query_async::<_, u8>(&mut con)
+            .await;
+
+        let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
+            .arg(Config::UnsubscribedKey.value())
+            .arg(0)
+            .query_async::<_, u8>(&mut con)
+            .await;
+
+        let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
+            .arg(Config::MigratedKey.value())
+            .arg(0)
+            .query_async::<_, u8>(&mut con)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn set<T: ToRedisArgs>(
+        &self,
+        config_key: Config,
+        value: T,
+    ) -> Result<(), RedisError> {
+        let operational_key: &str = config_key.value();
+
+        let mut con = self.con.clone();
+
+        let _: Result<(), redis::RedisError> = redis::cmd("SET")
+            .arg(operational_key)
+            .arg(value)
+            .query_async(&mut con)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn increase(&self, config_key: Config, amount: u64) -> Result<(), RedisError> {
+        let key: &str = config_key.value();
+
+        let mut con = self.con.clone();
+
+        let _: Result<(), redis::RedisError> = redis::cmd("INCRBY")
+            .arg(key)
+            .arg(amount)
+            .query_async(&mut con)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn get<T: FromRedisValue>(&self, config_key: Config) -> Option<T> {
+        let key: &str = config_key.value();
+
+        let mut con = self.con.clone();
+
+        let result: Result<T, redis::RedisError> =
+            redis::cmd("GET").arg(key).query_async(&mut con).await;
+
+        if let Ok(r) = result {
+            return Some(r);
+        }
+
+        None
+    }
+
+    // Expires by itself, so a service that crashed while holding it doesn't block the others
+    pub async fn acquire_lock(&self, config_key: Config, seconds: u64) -> Result<bool, RedisError> {
+        let mut con = self.con.clone();
+
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(config_key.value())
+            .arg(get_timestamp())
+            .arg("NX")
+            .arg("EX")
+            .arg(seconds)
+            .query_async(&mut con)
+            .await?;
+
+        Ok(acquired.is_some())
+    }
+
+    pub async fn release_lock(&self, config_key: Config) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        redis::cmd("DEL")
+            .arg(config_key.value())
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        Ok(())
+    }
+
+    // Takes a token from the bucket when there is one, otherwise returns how long to wait
+    pub async fn take_token(
+        &self,
+        config_key: Config,
+        capacity: f64,
+        per_second: f64,
+    ) -> Result<Duration, RedisError> {
+        let mut con = self.con.clone();
+
+        let wait: u64 = Script::new(TAKE_TOKEN_SCRIPT)
+            .key(config_key.value())
+            .arg(capacity)
+            .arg(per_second)
+            .invoke_async(&mut con)
+            .await?;
+
+        Ok(Duration::from_millis(wait))
+    }
+
+    pub async fn pause_bucket(
+        &self,
+        config_key: Config,
+        duration: Duration,
+    ) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        Script::new(PAUSE_BUCKET_SCRIPT)
+            .key(config_key.value())
+            .arg(duration.as_millis() as u64)
+            .invoke_async::<_, ()>(&mut con)
+            .await?;
+
+        Ok(())
+    }
+
+    // The admin can stop the bot, it runs unless told otherwise
+    pub async fn is_operational(&self) -> bool {
+        let is_operational: String = self
+            .get(Config::OperationalKey)
+            .await
+            .unwrap_or("1".to_string());
+
+        is_operational.eq("1")
+    }
+}
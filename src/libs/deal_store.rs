@@ -0,0 +1,601 @@
+use log::info;
+use redis::aio::ConnectionManager;
+use redis::streams::{
+    StreamId, StreamMaxlen, StreamRangeReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{AsyncCommands, FromRedisValue, RedisResult, Script};
+use std::collections::HashMap;
+
+use crate::libs::category::SeenCategory;
+use crate::libs::redis::{connect_database, get_timestamp, Database, RedisError};
+use crate::libs::stream::{get_claim_idle_milliseconds, get_max_length, CONSUMER_GROUP};
+use crate::structs::graphql_response::DealSearch;
+use crate::structs::message::{DeadLetter, Message, MessageError, LIST_NAME};
+
+pub static TRACKED_DEALS_KEY: &str = "tracked_deals";
+
+// Deals were queued in a list before the stream existed, it is renamed to this while migrating
+static LEGACY_LIST_NAME: &str = "deals:legacy";
+// Every stream entry holds the JSON of a message in this field
+static STREAM_FIELD: &str = "message";
+
+// Sends that kept failing, newest first and capped at DEAD_LETTER_MAX_LENGTH entries
+pub static DEAD_LETTER_KEY: &str = "deals:dead";
+static DEAD_LETTER_MAX_LENGTH: isize = 1000;
+
+// Chats with buffered deals, scored by the time their digest is due
+pub static DIGEST_DUE_KEY: &str = "digest_due";
+static DIGEST_MAX_LENGTH: isize = 100;
+
+// Category names Pepper put on new deals, per instance: the amount of deals and the last
+// time one came by
+static CATEGORY_COUNT_KEY: &str = "categories:count";
+static CATEGORY_LAST_SEEN_KEY: &str = "categories:last_seen";
+// Deals whose categories were counted already, a deal shows up in several feeds and polls
+static CATEGORY_RECORDED_KEY: &str = "categories:recorded";
+
+// /deals searches are kept for a day with their results, the buttons below the results
+// only carry their id
+static SEARCH_KEY: &str = "search";
+static SEARCH_EXPIRY_SECONDS: usize = 86400;
+
+// Sent deals are remembered this long, so they don't get sent twice
+static SENT_EXPIRY_SECONDS: u64 = 172800;
+
+// Marks a deal as sent by the stream entry in ARGV[1], unless another entry marked it first
+static CLAIM_MESSAGE_SCRIPT: &str = r"
+local sent_by = redis.call('GET', KEYS[1])
+if sent_by and sent_by ~= ARGV[1] then
+    return 0
+end
+
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+
+return 1
+";
+
+// The deal stream with everything around it: sent deals, dead letters, digests and tracking
+#[derive(Clone)]
+pub struct DealStore {
+    con: ConnectionManager,
+}
+
+impl DealStore {
+    pub async fn connect(redis_url: &str) -> Result<DealStore, RedisError> {
+        Ok(DealStore {
+            con: connect_database(redis_url, Database::MESSAGE).await?,
+        })
+    }
+
+    // Deals that were sent before keep their key until it expires
+    pub async fn is_known(&self, id: &str) -> Result<bool, RedisError> {
+        let mut con = self.con.clone();
+
+        Ok(con.exists(id).await?)
+    }
+
+    // Marks the message as sent by this stream entry, false when another entry sent it
+    // already. An entry claimed from a crashed consumer finds its own mark, so it gets
+    // to finish sending
+    pub async fn claim_message(&self, id: &str, stream_id: &str) -> Result<bool, RedisError> {
+        let mut con = self.con.clone();
+
+        Ok(Script::new(CLAIM_MESSAGE_SCRIPT)
+            .key(id)
+            .arg(stream_id)
+            .arg(SENT_EXPIRY_SECONDS)
+            .invoke_async(&mut con)
+            .await?)
+    }
+
+    // Resets the idle time of an entry that is still being sent, otherwise a long fan-out
+    // gets taken over by another consumer halfway
+    pub async fn keep_claimed(&self, consumer: &str, stream_id: &str) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        redis::cmd("XCLAIM")
+            .arg(LIST_NAME)
+            .arg(CONSUMER_GROUP)
+            .arg(consumer)
+            .arg(0)
+            .arg(stream_id)
+            .arg("JUSTID")
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn publish_message(&self, message: Message) -> Result<(), MessageError> {
+        let mut con = self.con.clone();
+
+        if let Ok(json) = serde_json::to_string(&message) {
+            match con
+                .xadd_maxlen::<String, &str, &str, String, String>(
+                    message.list.clone(),
+                    StreamMaxlen::Approx(get_max_length()),
+                    "*",
+                    &[(STREAM_FIELD, json.clone())],
+                )
+                .await
+            {
+                Ok(e) => {
+                    info!(
+                        "[{}] Added message to stream {}: {}",
+                        e,
+                        message.list,
+                        json.clone()
+                    );
+                    return Ok(());
+                }
+                Err(e) => return Err(MessageError::RedisError(e)),
+            }
+        }
+
+        Err(MessageError::ParseError)
+    }
+
+    // Deals used to be queued in a list under the same key, those are moved into the stream
+    // before the consumer group gets created
+    pub async fn create_message_stream(&self) -> Result<(), MessageError> {
+        let mut con = self.con.clone();
+
+        let key_type: String = redis::cmd("TYPE")
+            .arg(LIST_NAME)
+            .query_async(&mut con)
+            .await?;
+        if key_type.eq("list") {
+            let _: Result<(), redis::RedisError> = redis::cmd("RENAMENX")
+                .arg(LIST_NAME)
+                .arg(LEGACY_LIST_NAME)
+                .query_async(&mut con)
+                .await;
+        }
+
+        // Another service might have started the migration, popping keeps it safe to run twice
+        let mut migrated = 0;
+        while let Some(json) = con
+            .lpop::<&str, Option<String>>(LEGACY_LIST_NAME, None)
+            .await?
+        {
+            con.xadd::<&str, &str, &str, String, String>(LIST_NAME, "*", &[(STREAM_FIELD, json)])
+                .await?;
+            migrated += 1;
+        }
+
+        if migrated > 0 {
+            info!(
+                "Migrated {} messages from the {} list to the stream",
+                migrated, LIST_NAME
+            );
+        }
+
+        let created: RedisResult<()> = redis::cmd("XGROUP")
+            .arg("CREATE")
+            .arg(LIST_NAME)
+            .arg(CONSUMER_GROUP)
+            .arg("0")
+            .arg("MKSTREAM")
+            .query_async(&mut con)
+            .await;
+
+        match created {
+            Err(e) if e.code() != Some("BUSYGROUP") => Err(MessageError::RedisError(e)),
+            _ => Ok(()),
+        }
+    }
+
+    // Returns the stream id with the message. Messages a crashed consumer left unacknowledged
+    // are taken over first, otherwise this waits at most `timeout` seconds for a new message,
+    // so callers can check for a shutdown in between. Blocking holds up the connection, so
+    // the consumer reads from a store of its own
+    pub async fn read_message(&self, consumer: &str, timeout: usize) -> Option<(String, Message)> {
+        let mut con = self.con.clone();
+
+        let claimed: RedisResult<Vec<redis::Value>> = redis::cmd("XAUTOCLAIM")
+            .arg(LIST_NAME)
+            .arg(CONSUMER_GROUP)
+            .arg(consumer)
+            .arg(get_claim_idle_milliseconds())
+            .arg("0")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(&mut con)
+            .await;
+
+        let mut entries: Vec<StreamId> = vec![];
+        if let Ok(reply) = claimed {
+            if let Some(Ok(range)) = reply.get(1).map(StreamRangeReply::from_redis_value) {
+                entries = range.ids;
+            }
+        }
+
+        if entries.is_empty() {
+            let options = StreamReadOptions::default()
+                .group(CONSUMER_GROUP, consumer)
+                .count(1)
+                .block(timeout * 1000);
+
+            let read: RedisResult<StreamReadReply> =
+                con.xread_options(&[LIST_NAME], &[">"], &options).await;
+
+            if let Ok(reply) = read {
+                entries = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+            }
+        }
+
+        let entry = entries.into_iter().next()?;
+        let json: Option<String> = entry.get(STREAM_FIELD);
+
+        match json.and_then(|j| serde_json::from_str::<Message>(&j).ok()) {
+            Some(message) => Some((entry.id, message)),
+            None => {
+                // Nobody will ever be able to read this one, don't let it get claimed forever
+                info!("Dropping unreadable message {}", entry.id);
+                let _: RedisResult<i32> = con.xack(LIST_NAME, CONSUMER_GROUP, &[&entry.id]).await;
+                None
+            }
+        }
+    }
+
+    pub async fn acknowledge_message(&self, id: &str) -> Result<(), MessageError> {
+        let mut con = self.con.clone();
+
+        con.xack::<&str, &str, &str, i32>(LIST_NAME, CONSUMER_GROUP, &[id])
+            .await?;
+
+        Ok(())
+    }
+
+    // Adds a message to the stream again, used to hand the remaining recipients of a
+    // message over to the next consumer
+    pub async fn requeue_message(&self, message: &Message) -> Result<(), MessageError> {
+        let mut con = self.con.clone();
+
+        match serde_json::to_string(message) {
+            Ok(json) => {
+                con.xadd::<&str, &str, &str, String, String>(
+                    &message.list,
+                    "*",
+                    &[(STREAM_FIELD, json)],
+                )
+                .await?;
+                Ok(())
+            }
+            Err(_) => Err(MessageError::ParseError),
+        }
+    }
+
+    pub async fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), MessageError> {
+        let mut con = self.con.clone();
+
+        match serde_json::to_string(dead_letter) {
+            Ok(json) => {
+                con.lpush::<&str, String, i32>(DEAD_LETTER_KEY, json)
+                    .await?;
+                con.ltrim::<&str, ()>(DEAD_LETTER_KEY, 0, DEAD_LETTER_MAX_LENGTH - 1)
+                    .await?;
+                Ok(())
+            }
+            Err(_) => Err(MessageError::ParseError),
+        }
+    }
+
+    // Returns the total amount of dead letters with the newest `count` of them
+    pub async fn get_dead_letters(&self, count: isize) -> (usize, Vec<DeadLetter>) {
+        let mut con = self.con.clone();
+
+        let total: usize = con.llen(DEAD_LETTER_KEY).await.unwrap_or(0);
+        let entries: Vec<String> = con
+            .lrange(DEAD_LETTER_KEY, 0, count - 1)
+            .await
+            .unwrap_or_default();
+
+        let dead_letters = entries
+            .iter()
+            .filter_map(|json| serde_json::from_str::<DeadLetter>(json).ok())
+            .collect();
+
+        (total, dead_letters)
+    }
+
+    // Removes every dead letter from the list, oldest first
+    pub async fn take_dead_letters(&self) -> Vec<DeadLetter> {
+        let mut con = self.con.clone();
+
+        let mut dead_letters = vec![];
+        while let Ok(Some(json)) = con
+            .rpop::<&str, Option<String>>(DEAD_LETTER_KEY, None)
+            .await
+        {
+            if let Ok(dead_letter) = serde_json::from_str::<DeadLetter>(&json) {
+                dead_letters.push(dead_letter);
+            }
+        }
+
+        dead_letters
+    }
+
+    // Buffers a deal for the digest of a chat, which is sent at `due` unless an earlier
+    // deal already scheduled it. Only the latest deals are kept
+    pub async fn add_to_digest(
+        &self,
+        chat_id: &str,
+        message: &Message,
+        due: u64,
+    ) -> Result<(), MessageError> {
+        let mut con = self.con.clone();
+
+        let json = serde_json::to_string(message).map_err(|_| MessageError::ParseError)?;
+        let key = format!("digest:{}", chat_id);
+
+        con.rpush::<&str, String, i32>(&key, json).await?;
+        con.ltrim::<&str, ()>(&key, -DIGEST_MAX_LENGTH, -1).await?;
+
+        redis::cmd("ZADD")
+            .arg(DIGEST_DUE_KEY)
+            .arg("NX")
+            .arg(due)
+            .arg(chat_id)
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        Ok(())
+    }
+
+    // Returns the buffered deals of every chat whose digest is due. Removing the chat from
+    // the schedule claims it, so concurrent consumers don't send the same digest
+    pub async fn take_due_digests(&self) -> Vec<(String, Vec<Message>)> {
+        let mut con = self.con.clone();
+
+        let chat_ids: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(DIGEST_DUE_KEY)
+            .arg("-inf")
+            .arg(get_timestamp())
+            .query_async(&mut con)
+            .await
+            .unwrap_or_default();
+
+        let mut digests = vec![];
+        for chat_id in chat_ids {
+            let claimed: i32 = con.zrem(DIGEST_DUE_KEY, &chat_id).await.unwrap_or(0);
+            if claimed == 0 {
+                continue;
+            }
+
+            let key = format!("digest:{}", chat_id);
+            let buffered: RedisResult<(Vec<String>, ())> = redis::pipe()
+                .atomic()
+                .lrange(&key, 0, -1)
+                .del(&key)
+                .query_async(&mut con)
+                .await;
+
+            let messages: Vec<Message> = match buffered {
+                Ok((entries, _)) => entries
+                    .iter()
+                    .filter_map(|json| serde_json::from_str::<Message>(json).ok())
+                    .collect(),
+                Err(_) => vec![],
+            };
+
+            if !messages.is_empty() {
+                digests.push((chat_id, messages));
+            }
+        }
+
+        digests
+    }
+
+    pub async fn store_search(&self, search: &DealSearch) -> Result<u64, MessageError> {
+        let mut con = self.con.clone();
+
+        let json = serde_json::to_string(search).map_err(|_| MessageError::ParseError)?;
+
+        let id: u64 = con.incr(format!("{}:id", SEARCH_KEY), 1).await?;
+        con.set_ex::<_, _, ()>(
+            format!("{}:{}", SEARCH_KEY, id),
+            json,
+            SEARCH_EXPIRY_SECONDS,
+        )
+        .await?;
+
+        Ok(id)
+    }
+
+    pub async fn get_search(&self, id: u64) -> Option<DealSearch> {
+        let mut con = self.con.clone();
+
+        let json: Option<String> = con
+            .get(format!("{}:{}", SEARCH_KEY, id))
+            .await
+            .ok()
+            .flatten();
+
+        json.and_then(|j| serde_json::from_str::<DealSearch>(&j).ok())
+    }
+
+    // Chats that are gone for good don't get their buffered deals anymore
+    pub async fn drop_digest(&self, chat_id: &str) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        redis::pipe()
+            .atomic()
+            .zrem(DIGEST_DUE_KEY, chat_id)
+            .ignore()
+            .del(format!("digest:{}", chat_id))
+            .ignore()
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn record_categories(
+        &self,
+        instance: &str,
+        link: &str,
+        categories: &[String],
+    ) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+        let timestamp = get_timestamp();
+
+        let first_time: Option<String> = redis::cmd("SET")
+            .arg(format!("{}:{}", CATEGORY_RECORDED_KEY, link))
+            .arg(timestamp)
+            .arg("NX")
+            .arg("EX")
+            .arg(SENT_EXPIRY_SECONDS)
+            .query_async(&mut con)
+            .await?;
+
+        if first_time.is_none() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for category in categories {
+            pipe.hincr(format!("{}:{}", CATEGORY_COUNT_KEY, instance), category, 1)
+                .ignore()
+                .hset(
+                    format!("{}:{}", CATEGORY_LAST_SEEN_KEY, instance),
+                    category,
+                    timestamp,
+                )
+                .ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut con).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_seen_categories(&self, instance: &str) -> Vec<SeenCategory> {
+        let mut con = self.con.clone();
+
+        let counts: HashMap<String, u64> = con
+            .hgetall(format!("{}:{}", CATEGORY_COUNT_KEY, instance))
+            .await
+            .unwrap_or_default();
+        let last_seen: HashMap<String, u64> = con
+            .hgetall(format!("{}:{}", CATEGORY_LAST_SEEN_KEY, instance))
+            .await
+            .unwrap_or_default();
+
+        counts
+            .into_iter()
+            .map(|(name, deals)| SeenCategory {
+                last_seen: last_seen.get(&name).copied().unwrap_or_default(),
+                name,
+                deals,
+            })
+            .collect()
+    }
+
+    // Keeps the deal around so the temperature tracker can follow it for a while
+    pub async fn track_deal(
+        &self,
+        message: &Message,
+        tracking_seconds: u64,
+    ) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        if let Ok(json) = serde_json::to_string(message) {
+            let link = &message.payload.link;
+
+            redis::cmd("ZADD")
+                .arg(TRACKED_DEALS_KEY)
+                .arg("NX")
+                .arg(get_timestamp())
+                .arg(link)
+                .query_async::<_, ()>(&mut con)
+                .await?;
+
+            redis::cmd("SET")
+                .arg(format!("tracked:{}", link))
+                .arg(json)
+                .arg("EX")
+                .arg(tracking_seconds)
+                .query_async::<_, ()>(&mut con)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_tracked_deals(&self, tracking_seconds: u64) -> Vec<Message> {
+        let mut con = self.con.clone();
+
+        let since = get_timestamp().saturating_sub(tracking_seconds);
+
+        // Forget about deals that are too old to still become hot
+        let _: Result<(), redis::RedisError> = redis::cmd("ZREMRANGEBYSCORE")
+            .arg(TRACKED_DEALS_KEY)
+            .arg("-inf")
+            .arg(format!("({}", since))
+            .query_async(&mut con)
+            .await;
+
+        let links: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(TRACKED_DEALS_KEY)
+            .arg(since)
+            .arg("+inf")
+            .query_async(&mut con)
+            .await
+            .unwrap_or_default();
+
+        let mut messages: Vec<Message> = vec![];
+        for link in links {
+            let json: Option<String> = redis::cmd("GET")
+                .arg(format!("tracked:{}", link))
+                .query_async(&mut con)
+                .await
+                .unwrap_or(None);
+
+            if let Some(message) = json.and_then(|j| serde_json::from_str::<Message>(&j).ok()) {
+                messages.push(message);
+            }
+        }
+
+        messages
+    }
+
+    // Stores a temperature measurement and returns the one measured before it
+    pub async fn add_temperature(
+        &self,
+        link: &str,
+        temperature: f64,
+        tracking_seconds: u64,
+    ) -> Result<Option<f64>, RedisError> {
+        let mut con = self.con.clone();
+
+        let key = format!("temperature:{}", link);
+        let timestamp = get_timestamp();
+
+        let last: Vec<String> = redis::cmd("ZRANGE")
+            .arg(&key)
+            .arg(-1)
+            .arg(-1)
+            .query_async(&mut con)
+            .await?;
+        let previous = last
+            .first()
+            .and_then(|m| m.split_once(':'))
+            .and_then(|(_, t)| t.parse::<f64>().ok());
+
+        redis::cmd("ZADD")
+            .arg(&key)
+            .arg(timestamp)
+            .arg(format!("{}:{}", timestamp, temperature))
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        redis::cmd("EXPIRE")
+            .arg(&key)
+            .arg(tracking_seconds)
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        Ok(previous)
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::libs::quiet::resolve_local_time;
+use crate::libs::variable::get_optional_variable;
+
+// How often the consumer looks for digests that are due
+pub static DIGEST_CHECK_SECONDS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Digest {
+    Hourly,
+    Daily,
+}
+
+impl Digest {
+    pub fn value(&self) -> &str {
+        match *self {
+            Digest::Hourly => "hourly",
+            Digest::Daily => "daily",
+        }
+    }
+
+    pub fn from_value(value: &str) -> Option<Digest> {
+        match value.trim().to_lowercase().as_str() {
+            "hourly" => Some(Digest::Hourly),
+            "daily" => Some(Digest::Daily),
+            _ => None,
+        }
+    }
+
+    // Hourly digests go out at the start of every hour, daily digests at
+    // DIGEST_DAILY_HOUR (8 by default) in the time zone of the subscriber
+    pub fn next_due(&self, timestamp: u64, timezone: Tz) -> u64 {
+        match self {
+            Digest::Hourly => timestamp - timestamp % 3600 + 3600,
+            Digest::Daily => {
+                let send_at = NaiveTime::from_hms_opt(get_daily_hour(), 0, 0).unwrap_or_default();
+                let today = match Utc.timestamp_opt(timestamp as i64, 0).single() {
+                    Some(now) => now.with_timezone(&timezone).date_naive(),
+                    None => return timestamp + 86400,
+                };
+
+                // Early in the morning the digest still goes out today
+                today
+                    .iter_days()
+                    .take(2)
+                    .filter_map(|date| resolve_local_time(&date.and_time(send_at), timezone))
+                    .find(|due| *due > timestamp)
+                    .unwrap_or(timestamp + 86400)
+            }
+        }
+    }
+}
+
+fn get_daily_hour() -> u32 {
+    match get_optional_variable("DIGEST_DAILY_HOUR", 8) {
+        hour if hour < 24 => hour,
+        _ => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_timestamp(timezone: Tz, day: u32, hour: u32, minute: u32) -> u64 {
+        timezone
+            .with_ymd_and_hms(2024, 1, day, hour, minute, 0)
+            .single()
+            .unwrap()
+            .timestamp() as u64
+    }
+
+    #[test]
+    fn hourly_digest_rolls_over_to_the_next_hour() {
+        let tz = chrono_tz::UTC;
+
+        assert_eq!(
+            Digest::Hourly.next_due(local_timestamp(tz, 15, 10, 59), tz),
+            local_timestamp(tz, 15, 11, 0)
+        );
+        assert_eq!(
+            Digest::Hourly.next_due(local_timestamp(tz, 15, 11, 0), tz),
+            local_timestamp(tz, 15, 12, 0)
+        );
+        assert_eq!(
+            Digest::Hourly.next_due(local_timestamp(tz, 15, 23, 30), tz),
+            local_timestamp(tz, 16, 0, 0)
+        );
+    }
+
+    #[test]
+    fn daily_digest_follows_the_time_zone() {
+        let amsterdam = chrono_tz::Europe::Amsterdam;
+        let tokyo = chrono_tz::Asia::Tokyo;
+
+        assert_eq!(
+            Digest::Daily.next_due(local_timestamp(amsterdam, 15, 7, 30), amsterdam),
+            local_timestamp(amsterdam, 15, 8, 0)
+        );
+        assert_eq!(
+            Digest::Daily.next_due(local_timestamp(amsterdam, 15, 8, 0), amsterdam),
+            local_timestamp(amsterdam, 16, 8, 0)
+        );
+
+        // Early in the morning of the next day in Tokyo, well before Amsterdam's digest
+        let evening = local_timestamp(amsterdam, 15, 20, 0);
+        assert_eq!(
+            Digest::Daily.next_due(evening, tokyo),
+            local_timestamp(tokyo, 16, 8, 0)
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+
+static BREAK_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)<br\s*/?>|</p>|</li>|</div>").unwrap());
+static TAG_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+static WHITESPACE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[ \t\u{a0}]+").unwrap());
+static NEWLINE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\s*\n\s*").unwrap());
+static ENTITY_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(#[xX]?[0-9a-fA-F]+|[a-zA-Z]+);").unwrap());
+
+// Turns the HTML of a feed description into plain text
+pub fn strip_html(html: &str) -> String {
+    let text = BREAK_REGEX.replace_all(html, "\n");
+    let text = TAG_REGEX.replace_all(&text, "");
+    let text = decode_entities(&text);
+    let text = WHITESPACE_REGEX.replace_all(&text, " ");
+    let text = NEWLINE_REGEX.replace_all(&text, "\n");
+
+    text.trim().to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    ENTITY_REGEX
+        .replace_all(text, |captures: &Captures| {
+            let entity = &captures[1];
+
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                "euro" => Some('€'),
+                "pound" => Some('£'),
+                _ => match entity.strip_prefix('#') {
+                    Some(code) => match code.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => code.parse::<u32>().ok(),
+                    }
+                    .and_then(char::from_u32),
+                    None => None,
+                },
+            };
+
+            match decoded {
+                Some(c) => c.to_string(),
+                None => captures[0].to_string(),
+            }
+        })
+        .to_string()
+}
@@ -0,0 +1,225 @@
+use std::env;
+
+use crate::libs::category::CATEGORIES;
+
+// Subscribers that did not pick an instance receive the deals of this instance
+pub static DEFAULT_INSTANCE: &str = "nl";
+
+#[derive(Debug)]
+pub struct Instance {
+    pub name: &'static str,
+    pub base: &'static str,
+    pub host: &'static str,
+    pub deal_path: &'static str,
+    pub currency: &'static str,
+    pub decimal_separator: char,
+    pub feeds: &'static [(&'static str, &'static str)],
+    pub categories: &'static [&'static str],
+}
+
+pub static INSTANCES: [Instance; 5] = [
+    Instance {
+        name: "nl",
+        base: "https://nl.pepper.com",
+        host: "nl.pepper.com",
+        deal_path: "/aanbiedingen/",
+        currency: "€",
+        decimal_separator: ',',
+        feeds: &[
+            ("new", "/rss/nieuw"),
+            ("hot", "/rss/hot"),
+            ("vouchers", "/rss/kortingscodes"),
+            ("freebies", "/rss/gratis"),
+        ],
+        categories: &CATEGORIES,
+    },
+    Instance {
+        name: "de",
+        base: "https://www.mydealz.de",
+        host: "www.mydealz.de",
+        deal_path: "/deals/",
+        currency: "€",
+        decimal_separator: ',',
+        feeds: &[
+            ("new", "/rss/neu"),
+            ("hot", "/rss/hot"),
+            ("vouchers", "/rss/gutscheine"),
+            ("freebies", "/rss/freebies"),
+        ],
+        categories: &[
+            "Elektronik",
+            "Gaming",
+            "Lebensmittel & Haushalt",
+            "Fashion & Accessoires",
+            "Beauty & Gesundheit",
+            "Family & Kids",
+            "Home & Living",
+            "Garten & Baumarkt",
+            "Auto & Motorrad",
+            "Kultur & Freizeit",
+            "Sport & Outdoor",
+            "Telefon & Internet",
+            "Versicherung & Finanzen",
+            "Dienstleistungen & Verträge",
+            "Reisen",
+        ],
+    },
+    Instance {
+        name: "uk",
+        base: "https://www.hotukdeals.com",
+        host: "www.hotukdeals.com",
+        deal_path: "/deals/",
+        currency: "£",
+        decimal_separator: '.',
+        feeds: &[
+            ("new", "/rss/new"),
+            ("hot", "/rss/hot"),
+            ("vouchers", "/rss/vouchers"),
+            ("freebies", "/rss/freebies"),
+        ],
+        categories: &[
+            "Electronics",
+            "Gaming",
+            "Groceries",
+            "Fashion & Accessories",
+            "Health & Beauty",
+            "Family & Kids",
+            "Home & Living",
+            "Garden & DIY",
+            "Car & Motorcycle",
+            "Culture & Leisure",
+            "Sports & Outdoors",
+            "Phone & Internet",
+            "Insurance & Finance",
+            "Services & Contracts",
+            "Travel",
+        ],
+    },
+    Instance {
+        name: "fr",
+        base: "https://www.dealabs.com",
+        host: "www.dealabs.com",
+        deal_path: "/bons-plans/",
+        currency: "€",
+        decimal_separator: ',',
+        feeds: &[
+            ("new", "/rss/nouveaux"),
+            ("hot", "/rss/hot"),
+            ("vouchers", "/rss/codes-promo"),
+            ("freebies", "/rss/gratuit"),
+        ],
+        categories: &[
+            "High-tech",
+            "Consoles & Jeux vidéo",
+            "Épicerie & Courses",
+            "Mode & Accessoires",
+            "Santé & Cosmétiques",
+            "Famille & Enfants",
+            "Maison & Habitat",
+            "Jardin & Bricolage",
+            "Auto-Moto",
+            "Culture & Divertissement",
+            "Sports & Plein air",
+            "Téléphonie & Internet",
+            "Finances & Assurances",
+            "Services divers",
+            "Voyages",
+        ],
+    },
+    Instance {
+        name: "pl",
+        base: "https://www.pepper.pl",
+        host: "www.pepper.pl",
+        deal_path: "/promocje/",
+        currency: "zł",
+        decimal_separator: ',',
+        feeds: &[
+            ("new", "/rss/nowe"),
+            ("hot", "/rss/hot"),
+            ("vouchers", "/rss/kody-rabatowe"),
+            ("freebies", "/rss/za-darmo"),
+        ],
+        categories: &[
+            "Elektronika",
+            "Gry",
+            "Artykuły spożywcze",
+            "Moda i akcesoria",
+            "Zdrowie i uroda",
+            "Dla dzieci",
+            "Dom i mieszkanie",
+            "Ogród i majsterkowanie",
+            "Motoryzacja",
+            "Kultura i rozrywka",
+            "Sport i turystyka",
+            "Telefonia i internet",
+            "Finanse i ubezpieczenia",
+            "Usługi i subskrypcje",
+            "Podróże",
+        ],
+    },
+];
+
+impl Instance {
+    pub fn deal_url(&self, title_slug: &str, thread_id: &str) -> String {
+        format!(
+            "{}{}{}-{}",
+            self.base, self.deal_path, title_slug, thread_id
+        )
+    }
+
+    pub fn format_price(&self, price: f64) -> String {
+        let price = format!("{:.2}", price).replace('.', &self.decimal_separator.to_string());
+
+        match self.currency {
+            "zł" => format!("{} {}", price, self.currency),
+            _ => format!("{}{}", self.currency, price),
+        }
+    }
+}
+
+pub fn get_instance(name: &str) -> Option<&'static Instance> {
+    INSTANCES
+        .iter()
+        .find(|instance| instance.name.eq_ignore_ascii_case(name.trim()))
+}
+
+pub fn get_default_instance() -> &'static Instance {
+    get_instance(DEFAULT_INSTANCE).unwrap()
+}
+
+// Falls back to the default instance for unknown or missing names
+pub fn get_instance_or_default(name: Option<&str>) -> &'static Instance {
+    name.and_then(get_instance)
+        .unwrap_or_else(get_default_instance)
+}
+
+// Instances can be enabled with INSTANCES, formatted as `nl,de,uk`
+pub fn get_enabled_instances() -> Vec<&'static Instance> {
+    let enabled: Vec<&'static Instance> = env::var("INSTANCES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(get_instance)
+        .collect();
+
+    match enabled.is_empty() {
+        true => vec![get_default_instance()],
+        false => enabled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_price_keeps_cents() {
+        let nl = get_instance("nl").unwrap();
+        let uk = get_instance("uk").unwrap();
+        let pl = get_instance("pl").unwrap();
+
+        assert_eq!(nl.format_price(19.9), "€19,90");
+        assert_eq!(nl.format_price(5.0), "€5,00");
+        assert_eq!(uk.format_price(19.9), "£19.90");
+        assert_eq!(pl.format_price(1299.99), "1299,99 zł");
+    }
+}
@@ -1,8 +1,21 @@
 pub mod category;
+pub mod config_store;
+pub mod deal_store;
+pub mod digest;
+pub mod html;
+pub mod instance;
 pub mod middleware;
 pub mod pepper_request;
+pub mod polling;
+pub mod price;
+pub mod quiet;
+pub mod rate_limit;
 pub mod redis;
 pub mod rss;
+pub mod shutdown;
+pub mod stream;
+pub mod subscriber_store;
 pub mod telegram;
+pub mod temperature;
 pub mod variable;
 pub mod version;
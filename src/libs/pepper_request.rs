@@ -4,20 +4,46 @@ use reqwest::{
     header::{ACCEPT, CONTENT_TYPE, COOKIE, HOST, ORIGIN, REFERER, USER_AGENT},
     Client, Method, Response,
 };
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
 
-use crate::structs::graphql_response::GraphqlResponse;
+use crate::libs::instance::{get_default_instance, Instance};
+use crate::structs::graphql_response::{GraphqlResponse, ThreadResponse};
+
+// Deal links end with the thread id, like /aanbiedingen/some-title-123456
+pub fn get_thread_id(link: &str) -> Option<String> {
+    let thread_id: String = link
+        .trim_end_matches('/')
+        .rsplit('-')
+        .next()?
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+
+    match thread_id.is_empty() {
+        true => None,
+        false => Some(thread_id),
+    }
+}
 
 pub struct PepperRequest<'a> {
     base: &'a str,
+    host: &'a str,
     graphql_endpoint: &'a str,
     reqwest_client: Client,
 }
 
-impl PepperRequest<'_> {
-    pub fn new() -> Self {
+impl Default for PepperRequest<'_> {
+    fn default() -> Self {
+        Self::new(get_default_instance())
+    }
+}
+
+impl<'a> PepperRequest<'a> {
+    pub fn new(instance: &'a Instance) -> Self {
         PepperRequest {
-            base: "https://nl.pepper.com",
+            base: instance.base,
+            host: instance.host,
             graphql_endpoint: "/graphql",
             reqwest_client: reqwest::Client::new(),
         }
@@ -46,7 +72,7 @@ impl PepperRequest<'_> {
         headers.insert("X-Requested-With", "XMLHttpRequest".parse().unwrap());
         headers.insert(ORIGIN, self.base.parse().unwrap());
         headers.insert(REFERER, url.parse().unwrap());
-        headers.insert(HOST, "nl.pepper.com".parse().unwrap());
+        headers.insert(HOST, self.host.parse().unwrap());
 
         if let Some(cookie_headers) = cookie_headers {
             headers.insert(COOKIE, cookie_headers.join(" ").parse().unwrap());
@@ -56,11 +82,11 @@ impl PepperRequest<'_> {
             Method::GET => match self.reqwest_client.get(&url).headers(headers).send().await {
                 Ok(res) => {
                     info!("Requested: {}", url);
-                    return Some(res);
+                    Some(res)
                 }
                 Err(e) => {
                     warn!("{}", e);
-                    return None;
+                    None
                 }
             },
             Method::POST => match body {
@@ -78,17 +104,17 @@ impl PepperRequest<'_> {
                     {
                         Ok(res) => {
                             info!("Requested: {}", url);
-                            return Some(res);
+                            Some(res)
                         }
                         Err(e) => {
                             warn!("{}", e);
-                            return None;
+                            None
                         }
                     }
                 }
                 None => {
                     warn!("Body is required when doing a post");
-                    return None;
+                    None
                 }
             },
             _ => None,
@@ -117,7 +143,9 @@ impl PepperRequest<'_> {
         None
     }
 
-    pub async fn graphql(&self, search: &str) -> Option<GraphqlResponse> {
+    // Search suggestions come in a single batch of at most `limit` deals, the query takes no
+    // offset. /deals pages and sorts through that batch
+    pub async fn graphql(&self, search: &str, limit: usize) -> Option<GraphqlResponse> {
         if let Some(cookie_headers) = self.get_cookie_headers().await {
             let body = json!({
               "query": "query searchSuggestions(
@@ -148,25 +176,57 @@ impl PepperRequest<'_> {
                 }",
               "variables": {
                 "query": search,
-                "dealsLimit": 10
+                "dealsLimit": limit
               }
             });
 
-            if let Some(response) = self
-                .request(
-                    self.graphql_endpoint,
-                    Method::POST,
-                    Some(body),
-                    Some(cookie_headers),
-                )
-                .await
-            {
-                match response.json::<GraphqlResponse>().await {
-                    Ok(json) => return Some(json),
-                    Err(e) => {
-                        warn!("{:?}", e);
-                        return None;
-                    }
+            return self.query::<GraphqlResponse>(body, cookie_headers).await;
+        }
+
+        None
+    }
+
+    pub async fn thread(
+        &self,
+        thread_id: &str,
+        cookie_headers: Vec<String>,
+    ) -> Option<ThreadResponse> {
+        let body = json!({
+          "query": "query getThread($filter: IDFilter!) {
+              thread(threadId: $filter) {
+                threadId
+                temperature
+              }
+            }",
+          "variables": {
+            "filter": {
+              "eq": thread_id
+            }
+          }
+        });
+
+        self.query::<ThreadResponse>(body, cookie_headers).await
+    }
+
+    async fn query<T: DeserializeOwned>(
+        &self,
+        body: Value,
+        cookie_headers: Vec<String>,
+    ) -> Option<T> {
+        if let Some(response) = self
+            .request(
+                self.graphql_endpoint,
+                Method::POST,
+                Some(body),
+                Some(cookie_headers),
+            )
+            .await
+        {
+            match response.json::<T>().await {
+                Ok(json) => return Some(json),
+                Err(e) => {
+                    warn!("{:?}", e);
+                    return None;
                 }
             }
         }
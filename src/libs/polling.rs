@@ -0,0 +1,100 @@
+use log::info;
+use std::env;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::libs::variable::get_optional_variable;
+
+// Time to wait between polling rounds. It backs off while the feeds stay quiet,
+// speeds up again when new deals show up and never exceeds `busy_max` during
+// the busy hours (UTC) when most deals get posted. A throttling server is never
+// polled before it allows, not even during the busy hours.
+#[derive(Debug)]
+pub struct PollInterval {
+    current: Duration,
+    not_before: Option<Instant>,
+    min: Duration,
+    max: Duration,
+    busy_max: Duration,
+    busy_hours: (u64, u64),
+}
+
+fn get_seconds(key: &str, default: u64) -> Duration {
+    Duration::from_secs(get_optional_variable(key, default))
+}
+
+// Busy hours are configured with POLL_BUSY_HOURS, formatted as `6-21`
+fn get_busy_hours() -> (u64, u64) {
+    env::var("POLL_BUSY_HOURS")
+        .ok()
+        .and_then(|hours| {
+            let (start, end) = hours.split_once('-')?;
+            Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+        })
+        .unwrap_or((6, 21))
+}
+
+impl PollInterval {
+    pub fn from_env() -> PollInterval {
+        let min = get_seconds("POLL_MIN_SECONDS", 60);
+        let max = get_seconds("POLL_MAX_SECONDS", 900);
+
+        PollInterval {
+            current: get_seconds("POLL_INTERVAL_SECONDS", 300).clamp(min, max),
+            not_before: None,
+            min,
+            max,
+            busy_max: get_seconds("POLL_BUSY_MAX_SECONDS", 180),
+            busy_hours: get_busy_hours(),
+        }
+    }
+
+    pub fn found_new(&mut self) {
+        self.current = (self.current / 2).max(self.min);
+    }
+
+    pub fn found_nothing(&mut self) {
+        self.current = self.current.mul_f64(1.5).min(self.max);
+    }
+
+    // Waits at least as long as the server asked for, and otherwise backs off hard up
+    // to `max`
+    pub fn throttled(&mut self, retry_after: Option<Duration>) {
+        self.current = (self.current * 2).min(self.max);
+
+        let wait = match retry_after {
+            Some(retry_after) => retry_after.max(self.current),
+            None => self.current,
+        };
+
+        self.not_before = Some(Instant::now() + wait);
+    }
+
+    pub fn is_busy(&self) -> bool {
+        let hour = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() / 3600 % 24)
+            .unwrap_or(0);
+
+        let (start, end) = self.busy_hours;
+        match start <= end {
+            true => hour >= start && hour < end,
+            false => hour >= start || hour < end,
+        }
+    }
+
+    pub fn next(&self) -> Duration {
+        let interval = match self.is_busy() {
+            true => self.current.min(self.busy_max.max(self.min)),
+            false => self.current,
+        };
+
+        let interval = match self.not_before {
+            Some(not_before) => interval.max(not_before.saturating_duration_since(Instant::now())),
+            None => interval,
+        };
+
+        info!("Polling again in {} seconds", interval.as_secs());
+
+        interval
+    }
+}
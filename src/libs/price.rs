@@ -0,0 +1,70 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+static PRICE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[€£$]\s?\d[\d.,]*|\d[\d.,]*\s?(?:€|zł|EUR|euro)").unwrap());
+
+// Parses a displayed price like "€ 1.299,99", "£1,299.99" or "12,-" into a number
+pub fn parse_price(price: &str) -> Option<f64> {
+    let cleaned: String = price
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '.' || *c == ',')
+        .collect();
+    let cleaned = cleaned.trim_matches(|c| c == '.' || c == ',');
+
+    if cleaned.is_empty() {
+        return None;
+    }
+
+    let decimal_separator = match (cleaned.rfind('.'), cleaned.rfind(',')) {
+        // Both are used, the last one separates the decimals
+        (Some(dot), Some(comma)) => Some(if dot > comma { '.' } else { ',' }),
+        // A single separator only counts as decimal when followed by one or two digits
+        (Some(dot), None) if cleaned.len() - dot <= 3 => Some('.'),
+        (None, Some(comma)) if cleaned.len() - comma <= 3 => Some(','),
+        _ => None,
+    };
+
+    let normalized: String = cleaned
+        .chars()
+        .filter_map(|c| match c {
+            '0'..='9' => Some(c),
+            c if Some(c) == decimal_separator => Some('.'),
+            _ => None,
+        })
+        .collect();
+
+    normalized.parse::<f64>().ok()
+}
+
+// Finds every currency amount in a piece of text, in order of appearance
+pub fn find_prices(text: &str) -> Vec<f64> {
+    PRICE_REGEX
+        .find_iter(text)
+        .filter_map(|m| parse_price(m.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_price_reads_both_separators() {
+        assert_eq!(parse_price("€ 1.299,99"), Some(1299.99));
+        assert_eq!(parse_price("£1,299.99"), Some(1299.99));
+        assert_eq!(parse_price("19,9"), Some(19.9));
+        assert_eq!(parse_price("12,-"), Some(12.0));
+        assert_eq!(parse_price("1.299"), Some(1299.0));
+        assert_eq!(parse_price("gratis"), None);
+    }
+
+    #[test]
+    fn find_prices_keeps_order() {
+        assert_eq!(
+            find_prices("Van €49,99 voor 29,95 EUR, of 129,99 zł"),
+            vec![49.99, 29.95, 129.99]
+        );
+        assert!(find_prices("Nu 20% korting").is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use chrono::{Duration, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+// Subscribers that did not set a time zone are expected to live here
+pub static DEFAULT_TIMEZONE: Tz = chrono_tz::Europe::Amsterdam;
+
+// Clocks never skip more than this when summer time starts
+static MAX_GAP_MINUTES: i64 = 180;
+
+// Window of the day in which no deals are sent, in minutes since midnight. A window
+// that starts later than it ends runs past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+// Accepts `23:00`, `7:30` and `7`
+fn parse_time(value: &str) -> Option<u32> {
+    let (hours, minutes) = match value.trim().split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None => (value.trim(), "0"),
+    };
+
+    let hours = hours.trim().parse::<u32>().ok().filter(|h| *h < 24)?;
+    let minutes = minutes.trim().parse::<u32>().ok().filter(|m| *m < 60)?;
+
+    Some(hours * 60 + minutes)
+}
+
+// The timestamp of a local time. A time in the hour skipped when summer time starts
+// resolves to the moment the clock shows a time again
+pub fn resolve_local_time(local: &NaiveDateTime, timezone: Tz) -> Option<u64> {
+    (0..=MAX_GAP_MINUTES)
+        .find_map(|minutes| {
+            timezone
+                .from_local_datetime(&(*local + Duration::minutes(minutes)))
+                .earliest()
+        })
+        .map(|time| time.timestamp() as u64)
+}
+
+pub fn parse_timezone(value: &str) -> Option<Tz> {
+    let value = value.trim();
+
+    value.parse::<Tz>().ok().or_else(|| {
+        // Users tend to type europe/amsterdam, the database is case sensitive
+        chrono_tz::TZ_VARIANTS
+            .iter()
+            .find(|tz| tz.name().eq_ignore_ascii_case(value))
+            .copied()
+    })
+}
+
+impl QuietHours {
+    // Parses `23:00-07:00`
+    pub fn from_value(value: &str) -> Option<QuietHours> {
+        let (start, end) = value.split_once('-')?;
+
+        let quiet_hours = QuietHours {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+
+        match quiet_hours.start == quiet_hours.end {
+            true => None,
+            false => Some(quiet_hours),
+        }
+    }
+
+    pub fn value(&self) -> String {
+        format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        match self.start < self.end {
+            true => minute >= self.start && minute < self.end,
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+
+    // The moment the quiet hours end when `timestamp` falls within them
+    pub fn held_until(&self, timestamp: u64, timezone: Tz) -> Option<u64> {
+        let local = Utc
+            .timestamp_opt(timestamp as i64, 0)
+            .single()?
+            .with_timezone(&timezone);
+        let minute = local.hour() * 60 + local.minute();
+
+        if !self.contains(minute) {
+            return None;
+        }
+
+        // Late in the evening the window ends tomorrow
+        let mut date = local.date_naive();
+        if minute >= self.end {
+            date = date.succ_opt()?;
+        }
+
+        let end = date.and_time(NaiveTime::from_hms_opt(self.end / 60, self.end % 60, 0)?);
+
+        resolve_local_time(&end, timezone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_timestamp(timezone: Tz, (y, mo, d): (i32, u32, u32), (h, mi): (u32, u32)) -> u64 {
+        timezone
+            .with_ymd_and_hms(y, mo, d, h, mi, 0)
+            .single()
+            .unwrap()
+            .timestamp() as u64
+    }
+
+    #[test]
+    fn parse_time_accepts_hours_and_minutes() {
+        assert_eq!(parse_time("7"), Some(420));
+        assert_eq!(parse_time("7:30"), Some(450));
+        assert_eq!(parse_time(" 23:00 "), Some(1380));
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("23:60"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn from_value_rejects_empty_windows() {
+        let quiet_hours = QuietHours::from_value("23:00-7").unwrap();
+
+        assert_eq!(
+            quiet_hours,
+            QuietHours {
+                start: 1380,
+                end: 420
+            }
+        );
+        assert_eq!(quiet_hours.value(), "23:00-07:00");
+        assert_eq!(QuietHours::from_value("22:00-22:00"), None);
+        assert_eq!(QuietHours::from_value("22:00"), None);
+    }
+
+    #[test]
+    fn parse_timezone_ignores_case() {
+        assert_eq!(parse_timezone("europe/amsterdam"), Some(DEFAULT_TIMEZONE));
+        assert_eq!(
+            parse_timezone("Europe/London"),
+            Some(chrono_tz::Europe::London)
+        );
+        assert_eq!(parse_timezone("Mars/Olympus"), None);
+    }
+
+    #[test]
+    fn held_until_runs_past_midnight() {
+        let quiet_hours = QuietHours::from_value("23:00-07:00").unwrap();
+        let tz = DEFAULT_TIMEZONE;
+        let morning = local_timestamp(tz, (2024, 1, 16), (7, 0));
+
+        let evening = local_timestamp(tz, (2024, 1, 15), (23, 30));
+        assert_eq!(quiet_hours.held_until(evening, tz), Some(morning));
+
+        let night = local_timestamp(tz, (2024, 1, 16), (1, 0));
+        assert_eq!(quiet_hours.held_until(night, tz), Some(morning));
+
+        assert_eq!(quiet_hours.held_until(morning, tz), None);
+        let noon = local_timestamp(tz, (2024, 1, 16), (12, 0));
+        assert_eq!(quiet_hours.held_until(noon, tz), None);
+    }
+
+    #[test]
+    fn held_until_follows_summer_time() {
+        let tz = DEFAULT_TIMEZONE;
+
+        // The clocks skip from 02:00 to 03:00 that night, the night is an hour shorter
+        let quiet_hours = QuietHours::from_value("23:00-07:00").unwrap();
+        let evening = local_timestamp(tz, (2024, 3, 30), (23, 30));
+        let morning = local_timestamp(tz, (2024, 3, 31), (7, 0));
+        assert_eq!(morning - evening, 6 * 3600 + 1800);
+        assert_eq!(quiet_hours.held_until(evening, tz), Some(morning));
+
+        // An end within the skipped hour doesn't exist, the window ends when the clock
+        // continues at 03:00
+        let quiet_hours = QuietHours::from_value("23:00-02:30").unwrap();
+        let summer_time = local_timestamp(tz, (2024, 3, 31), (3, 0));
+
+        let night = local_timestamp(tz, (2024, 3, 31), (1, 30));
+        assert_eq!(quiet_hours.held_until(night, tz), Some(summer_time));
+
+        let evening = local_timestamp(tz, (2024, 3, 30), (23, 30));
+        assert_eq!(quiet_hours.held_until(evening, tz), Some(summer_time));
+        assert_eq!(quiet_hours.held_until(summer_time, tz), None);
+    }
+}
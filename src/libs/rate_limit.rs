@@ -0,0 +1,271 @@
+use log::{error, info};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::libs::config_store::ConfigStore;
+use crate::libs::redis::Config;
+use crate::libs::variable::get_optional_variable;
+
+// Pacing is logged at most once per this interval
+static LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+// Buckets of chats that were quiet for a while are dropped once there are this many
+static MAX_IDLE_BUCKETS: usize = 1000;
+
+fn get_rate(key: &str, default: f64) -> f64 {
+    match get_optional_variable(key, default) {
+        rate if rate > 0.0 => rate,
+        _ => default,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+    // Set when Telegram told us to back off
+    paused_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, refill_per_second: f64) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_per_second,
+            last_refill: Instant::now(),
+            paused_until: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    // Time until a token is available, zero when one can be taken right away
+    fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+
+        let paused = self.paused_for(now);
+
+        let empty = match self.tokens >= 1.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_second),
+        };
+
+        paused.max(empty)
+    }
+
+    fn paused_for(&self, now: Instant) -> Duration {
+        match self.paused_until {
+            Some(until) => until.saturating_duration_since(now),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    // A shorter pause never cuts an earlier, longer one short
+    fn pause(&mut self, now: Instant, duration: Duration) {
+        self.tokens = 0.0;
+        self.paused_until = self.paused_until.max(Some(now + duration));
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= self.capacity && self.paused_until.is_none_or(|until| until <= now)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    // Only paces this consumer on its own while Redis is unavailable
+    global: TokenBucket,
+    chats: HashMap<String, TokenBucket>,
+    // Pacing statistics since the last log line
+    sent: u64,
+    waited: Duration,
+    window_start: Instant,
+}
+
+// Keeps the sends within Telegram's limits: about 30 messages per second in total,
+// 1 per second to the same chat and 20 per minute to the same group. The rates can be
+// tuned with RATE_LIMIT_GLOBAL_PER_SECOND, RATE_LIMIT_CHAT_PER_SECOND and
+// RATE_LIMIT_GROUP_PER_MINUTE. The total applies to the bot, so its bucket is kept in Redis
+// and shared by every consumer
+pub struct RateLimiter {
+    global_per_second: f64,
+    chat_per_second: f64,
+    group_per_minute: f64,
+    config: ConfigStore,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn from_env(config: ConfigStore) -> RateLimiter {
+        let global_per_second = get_rate("RATE_LIMIT_GLOBAL_PER_SECOND", 30.0);
+
+        RateLimiter {
+            global_per_second,
+            config,
+            chat_per_second: get_rate("RATE_LIMIT_CHAT_PER_SECOND", 1.0),
+            group_per_minute: get_rate("RATE_LIMIT_GROUP_PER_MINUTE", 20.0),
+            buckets: Mutex::new(Buckets {
+                global: TokenBucket::new(global_per_second, global_per_second),
+                chats: HashMap::new(),
+                sent: 0,
+                waited: Duration::ZERO,
+                window_start: Instant::now(),
+            }),
+        }
+    }
+
+    // Group and channel ids are negative, those get the per-group budget
+    fn new_bucket(&self, chat_id: &str) -> TokenBucket {
+        match chat_id.starts_with('-') {
+            true => TokenBucket::new(self.group_per_minute, self.group_per_minute / 60.0),
+            false => TokenBucket::new(self.chat_per_second, self.chat_per_second),
+        }
+    }
+
+    // Waits until both the global and the chat budget allow another message
+    pub async fn acquire(&self, chat_id: &str) {
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().await;
+                let now = Instant::now();
+
+                if buckets.chats.len() > MAX_IDLE_BUCKETS {
+                    buckets.chats.retain(|_, bucket| !bucket.is_idle(now));
+                }
+
+                if !buckets.chats.contains_key(chat_id) {
+                    let bucket = self.new_bucket(chat_id);
+                    buckets.chats.insert(chat_id.to_string(), bucket);
+                }
+
+                let chat_wait = match buckets.chats.get_mut(chat_id) {
+                    Some(bucket) => bucket.wait_time(now),
+                    None => Duration::ZERO,
+                };
+
+                // A global token is only taken once the chat is allowed another message
+                let wait = match chat_wait.is_zero() {
+                    true => self.take_global(&mut buckets.global, now).await,
+                    false => chat_wait,
+                };
+
+                if wait.is_zero() {
+                    if let Some(bucket) = buckets.chats.get_mut(chat_id) {
+                        bucket.take();
+                    }
+
+                    buckets.sent += 1;
+                    Self::log_pacing(&mut buckets, now);
+                } else {
+                    buckets.waited += wait;
+                }
+
+                wait
+            };
+
+            if wait.is_zero() {
+                return;
+            }
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Zero when a token was taken from the shared bucket
+    async fn take_global(&self, local: &mut TokenBucket, now: Instant) -> Duration {
+        let paused = local.paused_for(now);
+        if !paused.is_zero() {
+            return paused;
+        }
+
+        let taken = self
+            .config
+            .take_token(
+                Config::RateLimitKey,
+                self.global_per_second,
+                self.global_per_second,
+            )
+            .await;
+
+        match taken {
+            Ok(wait) => wait,
+            Err(e) => {
+                error!("Taking a token from the global rate limit failed {:?}", e);
+
+                let wait = local.wait_time(now);
+                if wait.is_zero() {
+                    local.take();
+                }
+
+                wait
+            }
+        }
+    }
+
+    // Holds back messages for as long as Telegram asked in a RetryAfter. Flood waits usually
+    // apply to the whole bot, so every chat waits and not just the one that hit it
+    pub async fn pause(&self, chat_id: &str, duration: Duration) {
+        let mut buckets = self.buckets.lock().await;
+        let now = Instant::now();
+
+        info!(
+            "Telegram asked to wait {:?} before sending to {}",
+            duration, chat_id
+        );
+
+        let mut bucket = match buckets.chats.remove(chat_id) {
+            Some(bucket) => bucket,
+            None => self.new_bucket(chat_id),
+        };
+
+        bucket.pause(now, duration);
+        buckets.chats.insert(chat_id.to_string(), bucket);
+
+        buckets.global.pause(now, duration);
+
+        if let Err(e) = self
+            .config
+            .pause_bucket(Config::RateLimitKey, duration)
+            .await
+        {
+            error!("Pausing the global rate limit failed {:?}", e);
+        }
+    }
+
+    fn log_pacing(buckets: &mut Buckets, now: Instant) {
+        let elapsed = now.duration_since(buckets.window_start);
+
+        if elapsed < LOG_INTERVAL {
+            return;
+        }
+
+        info!(
+            "Sent {} messages in {}s ({:.2}/s), waited {:.1}s on rate limits, {} chats paced",
+            buckets.sent,
+            elapsed.as_secs(),
+            buckets.sent as f64 / elapsed.as_secs_f64(),
+            buckets.waited.as_secs_f64(),
+            buckets.chats.len()
+        );
+
+        buckets.sent = 0;
+        buckets.waited = Duration::ZERO;
+        buckets.window_start = now;
+    }
+}
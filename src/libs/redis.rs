@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use log::info;
-use redis::{Client, Commands, Connection, FromRedisValue, RedisResult, ToRedisArgs};
+use redis::aio::ConnectionManager;
+use redis::{Client, IntoConnectionInfo};
 use thiserror::Error;
 
-use crate::structs::message::{LIST_NAME, Message, MessageError};
+use crate::libs::config_store::ConfigStore;
+use crate::libs::deal_store::DealStore;
+use crate::libs::subscriber_store::SubscriberStore;
 
 #[derive(Error, Debug)]
 pub enum RedisError {
@@ -22,12 +24,53 @@ pub enum Database {
     SUBSCRIBER = 0,
     MESSAGE = 1,
     CONFIG = 2,
+    // Preferences were kept apart before subscribers became records, only read while migrating
+    PREFERENCE = 3,
+}
+
+// The stores of a service, each bound to its own database. A store holds a multiplexed
+// connection that reconnects on its own, so nothing has to SELECT a database and concurrent
+// requests can't end up in another one
+#[derive(Clone)]
+pub struct RedisConnections {
+    pub subscribers: SubscriberStore,
+    pub deals: DealStore,
+    pub config: ConfigStore,
+}
+
+impl RedisConnections {
+    pub async fn connect(redis_url: &str) -> Result<RedisConnections, RedisError> {
+        Ok(RedisConnections {
+            subscribers: SubscriberStore::connect(redis_url).await?,
+            deals: DealStore::connect(redis_url).await?,
+            config: ConfigStore::connect(redis_url).await?,
+        })
+    }
+
+    pub async fn is_available(&self) -> bool {
+        self.config.ping().await
+    }
+}
+
+pub async fn connect_database(
+    redis_url: &str,
+    database: Database,
+) -> Result<ConnectionManager, RedisError> {
+    let mut connection_info = redis_url.into_connection_info()?;
+    connection_info.redis.db = database as i64;
+
+    Ok(ConnectionManager::new(Client::open(connection_info)?).await?)
 }
 
 pub enum Config {
     OperationalKey,
     MessagesSentKey,
     DealsSentKey,
+    UnsubscribedKey,
+    MigratedKey,
+    SchemaVersionKey,
+    MigrationLockKey,
+    RateLimitKey,
 }
 
 impl Config {
@@ -36,191 +79,67 @@ impl Config {
             Config::OperationalKey => "is_operational",
             Config::MessagesSentKey => "messages_sent_count",
             Config::DealsSentKey => "deals_sent_count",
+            Config::UnsubscribedKey => "auto_unsubscribed_count",
+            Config::MigratedKey => "chats_migrated_count",
+            Config::SchemaVersionKey => "subscriber_schema_version",
+            Config::MigrationLockKey => "subscriber_migration_lock",
+            Config::RateLimitKey => "rate_limit_global",
         }
     }
 }
 
-pub async fn get_subscriber_amount(con: &mut Connection) -> usize {
-    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-        .arg(Database::SUBSCRIBER as u8)
-        .query(con);
-
-    let keys: Result<Vec<String>, redis::RedisError> = redis::cmd("KEYS").arg("*").query(con);
-
-    if let Ok(chat_ids) = keys {
-        return chat_ids.len();
-    }
-
-    0
+// Fields of the subscriber records
+#[derive(PartialEq)]
+pub enum Preference {
+    Categories,
+    Language,
+    CreatedAt,
+    Mode,
+    PausedUntil,
+    Keywords,
+    MaxPrice,
+    Feeds,
+    Instance,
+    HotThreshold,
+    ExcludedCategories,
+    QuietHours,
+    Timezone,
 }
 
-pub async fn get_subscribers(
-    redis_client: Client,
-) -> Result<HashMap<String, Option<Vec<String>>>, RedisError> {
-    if let Ok(mut con) = redis_client.get_connection() {
-        let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-            .arg(Database::SUBSCRIBER as u8)
-            .query(&mut con);
-
-        let keys: Result<Vec<String>, redis::RedisError> =
-            redis::cmd("KEYS").arg("*").query(&mut con);
-
-        if let Ok(chat_ids) = keys {
-            let mut subscribers: HashMap<String, Option<Vec<String>>> = HashMap::new();
-            for chat_id in &chat_ids {
-                let categories_string: String = redis::cmd("GET")
-                    .arg(chat_id)
-                    .query(&mut con)
-                    .unwrap_or("".to_string());
-
-                let categories: Vec<String> = categories_string
-                    .split(",")
-                    .map(|s| s.to_string())
-                    .collect();
-
-                // Fix for initial sign-ups
-                if categories.contains(&"1".to_string()) {
-                    subscribers.insert(chat_id.clone(), None);
-                } else {
-                    subscribers.insert(chat_id.clone(), Some(categories));
-                }
-            }
-
-            return Ok(subscribers);
+impl Preference {
+    pub fn value(&self) -> &str {
+        match *self {
+            Preference::Categories => "categories",
+            Preference::Language => "language",
+            Preference::CreatedAt => "created_at",
+            Preference::Mode => "mode",
+            Preference::PausedUntil => "paused_until",
+            Preference::Keywords => "keywords",
+            Preference::MaxPrice => "max_price",
+            Preference::Feeds => "feeds",
+            Preference::Instance => "instance",
+            Preference::HotThreshold => "hot_threshold",
+            Preference::ExcludedCategories => "excluded_categories",
+            Preference::QuietHours => "quiet_hours",
+            Preference::Timezone => "timezone",
         }
     }
-
-    Err(RedisError::NoSubscribers)
-}
-
-pub fn create_generic_config(redis_client: Client) -> Result<(), RedisError> {
-    let mut con: Connection = redis_client.get_connection()?;
-
-    // Make the current connection connect to the messages database
-    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-        .arg(Database::CONFIG as u8)
-        .query(&mut con);
-
-    let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
-        .arg(Config::OperationalKey.value())
-        .arg(1)
-        .query::<u8>(&mut con);
-
-    let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
-        .arg(Config::MessagesSentKey.value())
-        .arg(0)
-        .query::<u8>(&mut con);
-
-    let _: Result<u8, redis::RedisError> = redis::cmd("SETNX")
-        .arg(Config::DealsSentKey.value())
-        .arg(0)
-        .query::<u8>(&mut con);
-
-    Ok(())
 }
 
-pub fn set_config<T: ToRedisArgs>(
-    mut con: &mut Connection,
-    config_key: Config,
-    value: T,
-) -> Result<(), RedisError> {
-    let operational_key: &str = config_key.value();
-
-    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-        .arg(Database::CONFIG as u8)
-        .query(&mut con);
-
-    let _: Result<(), redis::RedisError> = redis::cmd("SET")
-        .arg(operational_key)
-        .arg(value)
-        .query(&mut con);
-
-    Ok(())
-}
-
-pub fn increase_config_value<T: FromRedisValue>(
-    mut con: &mut Connection,
-    config_key: Config,
-    next_database: Database,
-    amount: u8,
-) -> Result<(), RedisError> {
-    let key: &str = config_key.value();
-
-    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-        .arg(Database::CONFIG as u8)
-        .query(&mut con);
-
-    let _: Result<(), redis::RedisError> =
-        redis::cmd("INCRBY").arg(key).arg(amount).query(&mut con);
-
-    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-        .arg(next_database as u8)
-        .query(&mut con);
-
-    Ok(())
-}
-
-pub fn get_config<T: FromRedisValue>(
-    mut con: &mut Connection,
-    config_key: Config,
-    next_database: Database,
-) -> Option<T> {
-    let key: &str = config_key.value();
-
-    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-        .arg(Database::CONFIG as u8)
-        .query(&mut con);
-
-    let result: Result<T, redis::RedisError> = redis::cmd("GET").arg(key).query(&mut con);
-
-    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-        .arg(next_database as u8)
-        .query(&mut con);
-
-    if let Ok(r) = result {
-        return Some(r);
+pub fn split_preference(value: Option<String>) -> Vec<String> {
+    match value {
+        Some(v) => v
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        None => vec![],
     }
-
-    None
 }
 
-pub fn publish_message(redis_url: String, message: Message) -> Result<(), MessageError> {
-    match redis::Client::open(redis_url) {
-        Ok(redis_client) => match redis_client.get_connection() {
-            Ok(mut con) => {
-                if let Ok(json) = serde_json::to_string(&message) {
-                    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-                        .arg(Database::MESSAGE as u8)
-                        .query(&mut con);
-
-                    match con.rpush::<String, String, i32>(message.list.clone(), json.clone()) {
-                        Ok(e) => {
-                            info!("[{:?}] Added message to list {}: {}", e, message.list, json.clone());
-                            return Ok(());
-                        }
-                        Err(e) => return Err(MessageError::RedisError(e)),
-                    }
-                }
-
-                Err(MessageError::ParseError)
-            }
-            Err(e) => Err(MessageError::RedisError(e)),
-        },
-        Err(e) => Err(MessageError::RedisError(e)),
-    }
-}
-
-pub fn read_message(con: &mut Connection) -> Option<Message> {
-    // Make sure we're using the message database
-    let _: Result<(), redis::RedisError> =
-        redis::cmd("SELECT").arg(Database::MESSAGE as u8).query(con);
-
-    let read: RedisResult<(String, String)> = con.blpop(LIST_NAME, 0);
-    if let Ok((_list, list_message)) = read {
-        if let Ok(message) = serde_json::from_str::<Message>(&list_message) {
-            return Some(message);
-        }
-    }
-
-    None
+pub fn get_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
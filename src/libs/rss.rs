@@ -1,8 +1,36 @@
-use reqwest::header::{ACCEPT, ACCEPT_LANGUAGE, USER_AGENT};
-use rss::Channel;
+use reqwest::header::{
+    ACCEPT, ACCEPT_LANGUAGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+    USER_AGENT,
+};
+use reqwest::{Response, StatusCode};
+use rss::{Channel, Item};
+use std::env;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
-static RSS_URL: &str = "https://nl.pepper.com/rss/nieuw";
+use crate::libs::html::strip_html;
+use crate::libs::instance::Instance;
+use crate::libs::price::{find_prices, parse_price};
+
+// Subscribers that did not pick a feed receive the deals of this feed
+pub static DEFAULT_FEED: &str = "new";
+
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pub name: String,
+    pub url: String,
+    pub instance: &'static Instance,
+    // Validators of the last response, sent along to only get the feed when it changed
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub enum FeedResponse {
+    Modified(Box<Channel>),
+    NotModified,
+    // The server asked us to slow down, with the time to wait when it told us
+    Throttled(Option<Duration>),
+}
 
 #[derive(Error, Debug)]
 pub enum RSSError {
@@ -14,12 +42,56 @@ pub enum RSSError {
 
     #[error(transparent)]
     RedisError(#[from] redis::RedisError),
+
+    #[error("Feed responded with status {0}")]
+    StatusError(StatusCode),
+}
+
+// Feeds of an instance can be overridden with RSS_FEEDS_<INSTANCE>, formatted
+// as `name=url,name=url`
+pub fn get_feeds(instance: &'static Instance) -> Vec<Feed> {
+    let configured: Vec<Feed> = env::var(format!("RSS_FEEDS_{}", instance.name.to_uppercase()))
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|feed| feed.split_once('='))
+        .map(|(name, url)| Feed {
+            name: name.trim().to_lowercase(),
+            url: url.trim().to_string(),
+            instance,
+            etag: None,
+            last_modified: None,
+        })
+        .filter(|feed| !feed.name.is_empty() && !feed.url.is_empty())
+        .collect();
+
+    if !configured.is_empty() {
+        return configured;
+    }
+
+    instance
+        .feeds
+        .iter()
+        .map(|(name, path)| Feed {
+            name: name.to_string(),
+            url: format!("{}{}", instance.base, path),
+            instance,
+            etag: None,
+            last_modified: None,
+        })
+        .collect()
+}
+
+pub fn get_feed_names(instance: &'static Instance) -> Vec<String> {
+    get_feeds(instance)
+        .into_iter()
+        .map(|feed| feed.name)
+        .collect()
 }
 
-pub async fn get_rss_data() -> Result<Channel, RSSError> {
+pub async fn get_rss_data(feed: &mut Feed) -> Result<FeedResponse, RSSError> {
     let client = reqwest::Client::new();
-    let feed = client
-        .get(RSS_URL)
+    let mut request = client
+        .get(&feed.url)
         .header(USER_AGENT, "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/113.0.0.0 Safari/537.36")
         .header(ACCEPT, "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.9")
         .header(ACCEPT_LANGUAGE, "en-GB,en;q=0.6")
@@ -29,13 +101,112 @@ pub async fn get_rss_data() -> Result<Channel, RSSError> {
         .header("sec-fetch-site", "none")
         .header("sec-fetch-mod", "")
         .header("sec-fetch-user", "?1")
-        .header("sec-fetch-mode", "navigate")
-        .send()
-        .await?
-        .bytes()
-        .await?;
+        .header("sec-fetch-mode", "navigate");
+
+    if let Some(etag) = &feed.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+
+    if let Some(last_modified) = &feed.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let response = request.send().await?;
+
+    match response.status() {
+        StatusCode::NOT_MODIFIED => return Ok(FeedResponse::NotModified),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            return Ok(FeedResponse::Throttled(get_retry_after(&response)))
+        }
+        status if !status.is_success() => return Err(RSSError::StatusError(status)),
+        _ => (),
+    }
+
+    feed.etag = get_header(&response, ETAG);
+    feed.last_modified = get_header(&response, LAST_MODIFIED);
+
+    let body = response.bytes().await?;
+    let channel = Channel::read_from(&body[..])?;
+
+    Ok(FeedResponse::Modified(Box::new(channel)))
+}
+
+fn get_header(response: &Response, name: reqwest::header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+// Retry-After is either an amount of seconds or a date
+fn get_retry_after(response: &Response) -> Option<Duration> {
+    let value = get_header(response, RETRY_AFTER)?;
+
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    httpdate::parse_http_date(value.trim())
+        .ok()
+        .and_then(|date| date.duration_since(SystemTime::now()).ok())
+}
+
+// Pepper adds a `<pepper:merchant name="" price="" />` element to every item
+pub fn get_pepper_attribute(item: &Item, attribute: &str) -> Option<String> {
+    item.extensions
+        .get("pepper")
+        .and_then(|extensions| extensions.get("merchant"))
+        .and_then(|merchants| merchants.first())
+        .and_then(|merchant| merchant.attrs.get(attribute))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// Returns the price and, when the title mentions a higher one, the original price
+pub fn get_item_prices(item: &Item) -> (Option<f64>, Option<f64>) {
+    let title_prices = find_prices(item.title.as_deref().unwrap_or(""));
+
+    let price = get_pepper_attribute(item, "price")
+        .and_then(|p| parse_price(&p))
+        .or_else(|| title_prices.first().copied());
+
+    let original_price = match price {
+        Some(p) => title_prices.into_iter().find(|original| *original > p),
+        None => None,
+    };
+
+    (price, original_price)
+}
+
+pub fn get_item_description(item: &Item) -> Option<String> {
+    item.description
+        .as_deref()
+        .map(strip_html)
+        .filter(|description| !description.is_empty())
+}
+
+// Prefers an image enclosure, then the `<media:content>` and `<media:thumbnail>` tags
+pub fn get_item_image(item: &Item) -> Option<String> {
+    if let Some(enclosure) = &item.enclosure {
+        if enclosure.mime_type.starts_with("image") && !enclosure.url.is_empty() {
+            return Some(enclosure.url.clone());
+        }
+    }
 
-    let channel = Channel::read_from(&feed[..])?;
+    let media = item.extensions.get("media")?;
 
-    Ok(channel)
+    ["content", "thumbnail"]
+        .iter()
+        .filter_map(|tag| media.get(*tag))
+        .flatten()
+        .filter(|extension| {
+            extension
+                .attrs
+                .get("medium")
+                .is_none_or(|medium| medium.eq("image"))
+        })
+        .find_map(|extension| extension.attrs.get("url"))
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
 }
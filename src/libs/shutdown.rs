@@ -0,0 +1,93 @@
+use log::info;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::libs::variable::get_optional_variable;
+
+// Resolves on SIGTERM (sent by Kubernetes on a rollout) or ctrl-c
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => (),
+                    _ = tokio::signal::ctrl_c() => (),
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Time the in-flight work gets to finish after a shutdown signal, SHUTDOWN_GRACE_SECONDS
+pub fn get_grace_period() -> Duration {
+    Duration::from_secs(get_optional_variable("SHUTDOWN_GRACE_SECONDS", 25))
+}
+
+// Cheap to clone handle that tells every part of a service when to stop
+#[derive(Clone)]
+pub struct Shutdown {
+    deadline: watch::Receiver<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn listen() -> Shutdown {
+        let (sender, receiver) = watch::channel(None);
+        let grace_period = get_grace_period();
+
+        tokio::spawn(async move {
+            wait_for_signal().await;
+
+            info!(
+                "Received shutdown signal, stopping within {} seconds",
+                grace_period.as_secs()
+            );
+
+            let _ = sender.send(Some(Instant::now() + grace_period));
+
+            // Keep the sender alive, so receivers don't see the channel closing
+            std::future::pending::<()>().await;
+        });
+
+        Shutdown { deadline: receiver }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.deadline.borrow().is_some()
+    }
+
+    // True once the grace period ran out and in-flight work should be checkpointed
+    pub fn is_overdue(&self) -> bool {
+        match *self.deadline.borrow() {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    pub async fn wait(&mut self) {
+        while !self.is_triggered() {
+            if self.deadline.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Sleeps for the given duration, returns early with `false` when a shutdown comes in
+    pub async fn sleep(&mut self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = self.wait() => false,
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::env;
+use std::time::Duration;
+
+use crate::libs::variable::get_optional_variable;
+
+// Consumers of the deals stream share this group, every deal is handled by one of them
+pub static CONSUMER_GROUP: &str = "bot-consumers";
+
+// Name of this consumer within the group, the pod name when running in Kubernetes
+pub fn get_consumer_name() -> String {
+    env::var("CONSUMER_NAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or("bot-consumer".to_string())
+}
+
+// The stream is trimmed to roughly STREAM_MAX_LENGTH entries when adding deals
+pub fn get_max_length() -> usize {
+    get_optional_variable("STREAM_MAX_LENGTH", 10000)
+}
+
+// Deals a consumer did not acknowledge within STREAM_CLAIM_IDLE_SECONDS are taken over
+// by another consumer, so a crashed consumer doesn't lose them
+pub fn get_claim_idle_milliseconds() -> usize {
+    get_optional_variable("STREAM_CLAIM_IDLE_SECONDS", 600usize) * 1000
+}
+
+// Consumers renew the claim on the deal they are sending a few times per idle period
+pub fn get_claim_refresh_interval() -> Duration {
+    Duration::from_millis(get_claim_idle_milliseconds() as u64 / 4)
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::info;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, FromRedisValue, Script, ToRedisArgs};
+
+use crate::libs::config_store::ConfigStore;
+use crate::libs::redis::{
+    connect_database, get_timestamp, split_preference, Config, Database, Preference, RedisError,
+};
+use crate::structs::message::Message;
+use crate::structs::subscriber::Subscriber;
+
+// Bump when the layout of the subscriber records changes, migrate brings older records
+// up to date
+static SUBSCRIBER_SCHEMA_VERSION: u32 = 2;
+
+// Only one service migrates at a time, the lock expires in case it crashes halfway
+static MIGRATION_LOCK_SECONDS: u64 = 300;
+
+// Turns a legacy subscription into a record, unless another service converted or changed it
+// since it was read. ARGV holds the categories that were read followed by the record fields
+static CONVERT_LEGACY_SCRIPT: &str = r"
+if redis.call('TYPE', KEYS[1]).ok ~= 'string' or redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+
+redis.call('DEL', KEYS[1])
+redis.call('HSET', KEYS[1], unpack(ARGV, 2))
+
+return 1
+";
+
+// Sets of chat ids in the subscriber database: every subscriber, the ones without a category
+// filter and the ones watching keywords. Every category has a set of its own as well
+static SUBSCRIBER_INDEX_KEY: &str = "subscribers";
+static UNFILTERED_INDEX_KEY: &str = "subscribers:unfiltered";
+static KEYWORD_INDEX_KEY: &str = "subscribers:keywords";
+
+// Subscriber records are hashes keyed by chat id, kept next to their index sets
+#[derive(Clone)]
+pub struct SubscriberStore {
+    con: ConnectionManager,
+    // Preferences had a database of their own before, only read while migrating
+    legacy_preferences: ConnectionManager,
+}
+
+impl SubscriberStore {
+    pub async fn connect(redis_url: &str) -> Result<SubscriberStore, RedisError> {
+        Ok(SubscriberStore {
+            con: connect_database(redis_url, Database::SUBSCRIBER).await?,
+            legacy_preferences: connect_database(redis_url, Database::PREFERENCE).await?,
+        })
+    }
+
+    pub async fn get_amount(&self) -> usize {
+        let mut con = self.con.clone();
+
+        con.scard(SUBSCRIBER_INDEX_KEY).await.unwrap_or(0)
+    }
+
+    pub async fn get(&self, chat_id: &str) -> Option<Subscriber> {
+        let mut con = self.con.clone();
+
+        let record: HashMap<String, String> = con.hgetall(chat_id).await.unwrap_or_default();
+
+        Subscriber::from_record(record)
+    }
+
+    pub async fn get_all(&self) -> Result<HashMap<String, Subscriber>, RedisError> {
+        let mut con = self.con.clone();
+
+        let chat_ids: Result<Vec<String>, redis::RedisError> =
+            con.smembers(SUBSCRIBER_INDEX_KEY).await;
+
+        if let Ok(chat_ids) = chat_ids {
+            return get_subscriber_records(&mut con, chat_ids).await;
+        }
+
+        Err(RedisError::NoSubscribers)
+    }
+
+    // Only the chats that might want the message: the ones following any of its categories, all
+    // categories or keywords. Messages with recipients go to those chats alone
+    pub async fn get_interested(
+        &self,
+        message: &Message,
+    ) -> Result<HashMap<String, Subscriber>, RedisError> {
+        let mut con = self.con.clone();
+
+        let chat_ids: Result<Vec<String>, redis::RedisError> = match &message.recipients {
+            Some(recipients) => Ok(recipients.clone()),
+            None => {
+                let mut keys: Vec<String> = message
+                    .payload
+                    .categories
+                    .iter()
+                    .map(|category| get_category_index_key(category))
+                    .collect();
+                keys.push(UNFILTERED_INDEX_KEY.to_string());
+                keys.push(KEYWORD_INDEX_KEY.to_string());
+
+                con.sunion(keys).await
+            }
+        };
+
+        if let Ok(chat_ids) = chat_ids {
+            return get_subscriber_records(&mut con, chat_ids).await;
+        }
+
+        Err(RedisError::NoSubscribers)
+    }
+
+    // Subscribes the chat to the given categories, all categories when empty. Chats that
+    // were subscribed already keep their created_at and preferences
+    pub async fn add(
+        &self,
+        chat_id: &str,
+        categories: &[String],
+        language: Option<&str>,
+    ) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        let previous = get_indexed_categories(&mut con, chat_id).await;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_nx(chat_id, Preference::CreatedAt.value(), get_timestamp())
+            .ignore();
+
+        match categories.is_empty() {
+            true => pipe.hdel(chat_id, Preference::Categories.value()).ignore(),
+            false => pipe
+                .hset(
+                    chat_id,
+                    Preference::Categories.value(),
+                    categories.join(","),
+                )
+                .ignore(),
+        };
+
+        if let Some(language) = language {
+            pipe.hset(chat_id, Preference::Language.value(), language)
+                .ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut con).await?;
+
+        index_subscriber(&mut con, chat_id, previous).await
+    }
+
+    pub async fn remove(&self, chat_id: &str) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        let previous = get_indexed_categories(&mut con, chat_id).await;
+
+        redis::cmd("DEL")
+            .arg(chat_id)
+            .query_async::<_, ()>(&mut con)
+            .await?;
+
+        index_subscriber(&mut con, chat_id, previous).await
+    }
+
+    // Moves the subscriber record of a chat over to its new chat id
+    pub async fn move_to(&self, from: &str, to: &str) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        let exists: bool = con.exists(from).await?;
+        if exists {
+            let previous_from = get_indexed_categories(&mut con, from).await;
+            let previous_to = get_indexed_categories(&mut con, to).await;
+
+            con.rename::<&str, ()>(from, to).await?;
+
+            index_subscriber(&mut con, from, previous_from).await?;
+            index_subscriber(&mut con, to, previous_to).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_preference<T: FromRedisValue>(
+        &self,
+        chat_id: &str,
+        preference: Preference,
+    ) -> Option<T> {
+        let mut con = self.con.clone();
+
+        redis::cmd("HGET")
+            .arg(chat_id)
+            .arg(preference.value())
+            .query_async::<_, Option<T>>(&mut con)
+            .await
+            .unwrap_or(None)
+    }
+
+    // Passing `None` removes the preference, which brings back the default behaviour
+    pub async fn set_preference<T: ToRedisArgs>(
+        &self,
+        chat_id: &str,
+        preference: Preference,
+        value: Option<T>,
+    ) -> Result<(), RedisError> {
+        let mut con = self.con.clone();
+
+        // Only these fields decide which index sets the chat belongs to
+        let indexed = [
+            Preference::Categories,
+            Preference::Keywords,
+            Preference::CreatedAt,
+        ]
+        .contains(&preference);
+        let previous = match indexed {
+            true => get_indexed_categories(&mut con, chat_id).await,
+            false => vec![],
+        };
+
+        match value {
+            Some(v) => {
+                redis::cmd("HSET")
+                    .arg(chat_id)
+                    .arg(preference.value())
+                    .arg(v)
+                    .query_async::<_, ()>(&mut con)
+                    .await?
+            }
+            None => {
+                redis::cmd("HDEL")
+                    .arg(chat_id)
+                    .arg(preference.value())
+                    .query_async::<_, ()>(&mut con)
+                    .await?
+            }
+        };
+
+        match indexed {
+            true => index_subscriber(&mut con, chat_id, previous).await,
+            false => Ok(()),
+        }
+    }
+
+    pub async fn get_preference_list(&self, chat_id: &str, preference: Preference) -> Vec<String> {
+        split_preference(self.get_preference(chat_id, preference).await)
+    }
+
+    pub async fn set_preference_list(
+        &self,
+        chat_id: &str,
+        preference: Preference,
+        values: &[String],
+    ) -> Result<(), RedisError> {
+        match values.is_empty() {
+            true => {
+                self.set_preference::<String>(chat_id, preference, None)
+                    .await
+            }
+            false => {
+                self.set_preference(chat_id, preference, Some(values.join(",")))
+                    .await
+            }
+        }
+    }
+
+    // The schema version is kept with the config. Every service runs this on startup, the
+    // lock lets one of them migrate while the others wait for it to finish
+    pub async fn migrate(&self, config: &ConfigStore) -> Result<(), RedisError> {
+        loop {
+            let version: u32 = config.get(Config::SchemaVersionKey).await.unwrap_or(0);
+
+            if version >= SUBSCRIBER_SCHEMA_VERSION {
+                return Ok(());
+            }
+
+            if config
+                .acquire_lock(Config::MigrationLockKey, MIGRATION_LOCK_SECONDS)
+                .await?
+            {
+                let migrated = self.run_migrations(config).await;
+                let _ = config.release_lock(Config::MigrationLockKey).await;
+
+                return migrated;
+            }
+
+            info!("Waiting for another service to migrate the subscribers");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn run_migrations(&self, config: &ConfigStore) -> Result<(), RedisError> {
+        // Another service may have finished right before the lock was taken
+        let version: u32 = config.get(Config::SchemaVersionKey).await.unwrap_or(0);
+
+        if version >= SUBSCRIBER_SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        if version < 1 {
+            let migrated = self.migrate_legacy_subscribers().await?;
+            info!("Migrated {} subscribers to records", migrated);
+        }
+
+        if version < 2 {
+            let indexed = self.index_subscribers().await?;
+            info!("Indexed {} subscribers", indexed);
+        }
+
+        config
+            .set(Config::SchemaVersionKey, SUBSCRIBER_SCHEMA_VERSION)
+            .await
+    }
+
+    // Version 1: a subscription used to be a string holding "1" for all categories or a comma
+    // separated list of them, with the preferences in a hash of their own database
+    async fn migrate_legacy_subscribers(&self) -> Result<usize, RedisError> {
+        let mut migrated = 0;
+
+        let mut con = self.con.clone();
+        let script = Script::new(CONVERT_LEGACY_SCRIPT);
+
+        let chat_ids: Vec<String> = scan_keys(&mut con).await?;
+
+        for chat_id in &chat_ids {
+            let mut con = self.con.clone();
+
+            let key_type: String = redis::cmd("TYPE")
+                .arg(chat_id)
+                .query_async(&mut con)
+                .await?;
+            if key_type.ne("string") {
+                continue;
+            }
+
+            let categories: Option<String> = con.get(chat_id).await?;
+            let mut record = self.get_legacy_preferences(chat_id).await?;
+
+            record.insert(
+                Preference::CreatedAt.value().to_string(),
+                get_timestamp().to_string(),
+            );
+
+            let categories = categories.unwrap_or_default();
+            if !categories.is_empty() && categories.ne("1") {
+                record.insert(
+                    Preference::Categories.value().to_string(),
+                    categories.clone(),
+                );
+            }
+
+            let fields: Vec<(String, String)> = record.into_iter().collect();
+
+            let converted: bool = script
+                .key(chat_id)
+                .arg(&categories)
+                .arg(&fields)
+                .invoke_async(&mut con)
+                .await?;
+
+            // The preferences are only dropped once they made it into the record
+            if converted {
+                let mut con = self.legacy_preferences.clone();
+                con.del::<_, ()>(chat_id).await?;
+
+                migrated += 1;
+            }
+        }
+
+        // Chats that set preferences without ever subscribing
+        let mut con = self.legacy_preferences.clone();
+
+        let chat_ids: Vec<String> = scan_keys(&mut con).await?;
+
+        for chat_id in &chat_ids {
+            let mut con = self.con.clone();
+
+            // Subscriptions that were changed while converting keep their preferences until
+            // the next run
+            let key_type: String = redis::cmd("TYPE")
+                .arg(chat_id)
+                .query_async(&mut con)
+                .await?;
+            if key_type.eq("string") {
+                continue;
+            }
+
+            let record = self.take_legacy_preferences(chat_id).await?;
+            let fields: Vec<(String, String)> = record.into_iter().collect();
+
+            if fields.is_empty() {
+                continue;
+            }
+
+            con.hset_multiple::<_, _, _, ()>(chat_id, &fields).await?;
+        }
+
+        Ok(migrated)
+    }
+
+    // Version 2: the index sets were introduced, fill them for the existing records
+    async fn index_subscribers(&self) -> Result<usize, RedisError> {
+        let mut con = self.con.clone();
+
+        let chat_ids: Vec<String> = scan_keys(&mut con)
+            .await?
+            .into_iter()
+            .filter(|key| !key.starts_with(SUBSCRIBER_INDEX_KEY))
+            .collect();
+
+        for chat_id in &chat_ids {
+            let previous = get_indexed_categories(&mut con, chat_id).await;
+            index_subscriber(&mut con, chat_id, previous).await?;
+        }
+
+        Ok(chat_ids.len())
+    }
+
+    async fn get_legacy_preferences(
+        &self,
+        chat_id: &str,
+    ) -> Result<HashMap<String, String>, RedisError> {
+        let mut con = self.legacy_preferences.clone();
+
+        let preferences: HashMap<String, String> = con.hgetall(chat_id).await?;
+
+        Ok(rename_legacy_preferences(preferences))
+    }
+
+    async fn take_legacy_preferences(
+        &self,
+        chat_id: &str,
+    ) -> Result<HashMap<String, String>, RedisError> {
+        let mut con = self.legacy_preferences.clone();
+
+        let (preferences,): (HashMap<String, String>,) = redis::pipe()
+            .atomic()
+            .hgetall(chat_id)
+            .del(chat_id)
+            .ignore()
+            .query_async(&mut con)
+            .await?;
+
+        Ok(rename_legacy_preferences(preferences))
+    }
+}
+
+// The digest interval became the delivery mode
+fn rename_legacy_preferences(mut preferences: HashMap<String, String>) -> HashMap<String, String> {
+    if let Some(digest) = preferences.remove("digest") {
+        preferences.insert(Preference::Mode.value().to_string(), digest);
+    }
+
+    preferences
+}
+
+// Reads the records of the given chats in a single round trip
+async fn get_subscriber_records(
+    con: &mut ConnectionManager,
+    chat_ids: Vec<String>,
+) -> Result<HashMap<String, Subscriber>, RedisError> {
+    let mut pipe = redis::pipe();
+    for chat_id in &chat_ids {
+        pipe.hgetall(chat_id);
+    }
+
+    let records: Vec<HashMap<String, String>> = match chat_ids.is_empty() {
+        true => vec![],
+        false => pipe.query_async(con).await?,
+    };
+
+    Ok(chat_ids
+        .into_iter()
+        .zip(records)
+        .filter_map(|(chat_id, record)| {
+            Subscriber::from_record(record).map(|subscriber| (chat_id, subscriber))
+        })
+        .collect())
+}
+
+fn get_category_index_key(category: &str) -> String {
+    format!("{}:category:{}", SUBSCRIBER_INDEX_KEY, category)
+}
+
+// Brings the index sets in line with the record of the chat. The category sets can't be
+// told from the record once it changed, `previous` holds the categories it was indexed under
+async fn index_subscriber(
+    con: &mut ConnectionManager,
+    chat_id: &str,
+    previous: Vec<String>,
+) -> Result<(), RedisError> {
+    let record: HashMap<String, String> = con.hgetall(chat_id).await?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    for category in &previous {
+        pipe.srem(get_category_index_key(category), chat_id)
+            .ignore();
+    }
+
+    for key in [
+        SUBSCRIBER_INDEX_KEY,
+        UNFILTERED_INDEX_KEY,
+        KEYWORD_INDEX_KEY,
+    ] {
+        pipe.srem(key, chat_id).ignore();
+    }
+
+    if let Some(subscriber) = Subscriber::from_record(record) {
+        pipe.sadd(SUBSCRIBER_INDEX_KEY, chat_id).ignore();
+
+        match &subscriber.categories {
+            Some(categories) => {
+                for category in categories {
+                    pipe.sadd(get_category_index_key(category), chat_id)
+                        .ignore();
+                }
+            }
+            None => {
+                pipe.sadd(UNFILTERED_INDEX_KEY, chat_id).ignore();
+            }
+        }
+
+        if !subscriber.keywords.is_empty() {
+            pipe.sadd(KEYWORD_INDEX_KEY, chat_id).ignore();
+        }
+    }
+
+    pipe.query_async::<_, ()>(con).await?;
+
+    Ok(())
+}
+
+async fn get_indexed_categories(con: &mut ConnectionManager, chat_id: &str) -> Vec<String> {
+    split_preference(
+        con.hget(chat_id, Preference::Categories.value())
+            .await
+            .unwrap_or(None),
+    )
+}
+
+// Every key of the selected database, without blocking Redis the way KEYS does
+async fn scan_keys(con: &mut ConnectionManager) -> Result<Vec<String>, RedisError> {
+    let mut keys: Vec<String> = vec![];
+    let mut iter = con.scan::<String>().await?;
+
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
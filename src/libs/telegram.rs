@@ -1,28 +1,183 @@
 use log::info;
-use redis::Client;
+use std::collections::BTreeMap;
 use std::env;
-use teloxide::types::ParseMode;
-use teloxide::{prelude::*, utils::command::BotCommands, RequestError};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode};
+use teloxide::utils::html;
+use teloxide::utils::markdown::{escape, escape_link_url};
+use teloxide::{prelude::*, utils::command::BotCommands, ApiError, RequestError};
 use thiserror::Error;
 
-use crate::libs::category::match_category;
+use crate::libs::category::{get_live_categories, match_category, CategoryMatch};
+use crate::libs::config_store::ConfigStore;
+use crate::libs::digest::Digest;
+use crate::libs::instance::{get_instance, get_instance_or_default, Instance, INSTANCES};
+use crate::libs::price::parse_price;
+use crate::libs::quiet::{parse_timezone, QuietHours, DEFAULT_TIMEZONE};
+use crate::libs::rate_limit::RateLimiter;
+use crate::libs::rss::{get_feed_names, DEFAULT_FEED};
+use crate::libs::shutdown::{get_grace_period, Shutdown};
+use crate::libs::variable::get_optional_variable;
 use crate::libs::version::{get_app_version, get_helm_chart_version};
+use crate::structs::graphql_response::DealSearch;
+use crate::structs::message::{Deal, Message as DealMessage};
+use crate::structs::subscriber::Mode;
 
 use super::pepper_request::PepperRequest;
-use super::{
-    category::CATEGORIES,
-    redis::{get_subscribers, set_config, Config, Database},
-};
+use super::redis::{Config, Preference, RedisConnections};
 
 #[derive(Error, Debug)]
 pub enum BotError {
     #[error("No subscribers found")]
     NoSubscribers,
 
-    #[error(transparent)]
-    SendMessageError(#[from] RequestError),
+    // Network trouble, Telegram server errors and rate limits, worth another try
+    #[error("Transient send failure: {0}")]
+    TransientError(RequestError),
+
+    // Telegram refused the message, sending it again gives the same result
+    #[error("Permanent send failure: {0}")]
+    PermanentError(RequestError),
+}
+
+impl From<RequestError> for BotError {
+    fn from(error: RequestError) -> Self {
+        let is_transient = match &error {
+            RequestError::Network(_)
+            | RequestError::RetryAfter(_)
+            | RequestError::Io(_)
+            | RequestError::InvalidJson { .. } => true,
+            // Server errors don't have their own ApiError, only their description tells
+            RequestError::Api(ApiError::Unknown(description)) => {
+                let description = description.to_lowercase();
+
+                [
+                    "internal server error",
+                    "bad gateway",
+                    "gateway timeout",
+                    "service unavailable",
+                ]
+                .iter()
+                .any(|server_error| description.contains(server_error))
+            }
+            _ => false,
+        };
+
+        match is_transient {
+            true => BotError::TransientError(error),
+            false => BotError::PermanentError(error),
+        }
+    }
+}
+
+impl BotError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, BotError::TransientError(_))
+    }
+
+    // The user blocked the bot, deleted their account or the group no longer exists
+    pub fn is_chat_gone(&self) -> bool {
+        matches!(
+            self,
+            BotError::PermanentError(RequestError::Api(
+                ApiError::BotBlocked
+                    | ApiError::ChatNotFound
+                    | ApiError::UserDeactivated
+                    | ApiError::BotKicked
+                    | ApiError::BotKickedFromSupergroup
+                    | ApiError::GroupDeactivated
+                    | ApiError::CantInitiateConversation
+            ))
+        )
+    }
+
+    // A group that was upgraded to a supergroup continues under a new chat id
+    pub fn migrated_to(&self) -> Option<String> {
+        match self {
+            BotError::PermanentError(RequestError::MigrateToChatId(chat_id)) => {
+                Some(chat_id.to_string())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            BotError::TransientError(RequestError::RetryAfter(wait)) => Some(*wait),
+            _ => None,
+        }
+    }
+}
+
+// Attempts per send before a message is put on the dead-letter list, SEND_MAX_ATTEMPTS
+fn get_send_attempts() -> u32 {
+    get_optional_variable("SEND_MAX_ATTEMPTS", 4u32).max(1)
+}
+
+// Waits 1, 2, 4... seconds between attempts, up to a minute
+fn get_backoff(attempt: u32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempt.saturating_sub(1)).min(60))
+}
+
+// Deals shown per page of /deals, DEALS_PAGE_SIZE
+fn get_deals_page_size() -> usize {
+    match get_optional_variable("DEALS_PAGE_SIZE", 10) {
+        0 => 10,
+        size => size,
+    }
+}
+
+// Deals fetched per search to page and sort through, DEALS_SEARCH_LIMIT
+fn get_deals_search_limit() -> usize {
+    match get_optional_variable("DEALS_SEARCH_LIMIT", 50) {
+        0 => 50,
+        limit => limit,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DealSort {
+    Relevance,
+    Temperature,
+    Price,
+}
+
+impl DealSort {
+    fn value(&self) -> &str {
+        match *self {
+            DealSort::Relevance => "relevance",
+            DealSort::Temperature => "temperature",
+            DealSort::Price => "price",
+        }
+    }
+
+    fn from_value(value: &str) -> Option<DealSort> {
+        match value {
+            "relevance" => Some(DealSort::Relevance),
+            "temperature" => Some(DealSort::Temperature),
+            "price" => Some(DealSort::Price),
+            _ => None,
+        }
+    }
 }
 
+// Callback data of the deal buttons is formatted as `<action>:<value>`
+static MUTE_CALLBACK: &str = "mute";
+static SIMILAR_CALLBACK: &str = "similar";
+
+// The buttons below /deals results carry `<sort>:<page>:<search id>` as value
+static DEALS_CALLBACK: &str = "deals";
+
+// Buttons of the category picker carry the index of the category in the instance's list
+static CATEGORY_CALLBACK: &str = "category";
+static CATEGORY_ALL: &str = "all";
+static CATEGORY_DONE: &str = "done";
+
+// Telegram refuses callback data longer than this
+const MAX_CALLBACK_DATA_BYTES: usize = 64;
+
 #[derive(BotCommands, Clone, Debug)]
 #[command(
     rename_rule = "lowercase",
@@ -38,14 +193,46 @@ enum Command {
     #[command(description = "Cancel subscription for Pepperbot")]
     Stop,
     #[command(
-        description = "Signup for one of the Pepper categories. Only accepts comma-separated categories"
+        description = "Signup for one of the Pepper categories. Accepts comma-separated categories or opens a picker without them"
     )]
     Categories,
+    #[command(
+        description = "Receive everything except these comma-separated categories. Use /exclude without categories to disable"
+    )]
+    Exclude,
     #[command(
         rename = "available_categories",
         description = "List available Pepper categories"
     )]
     AvailableCategories,
+    #[command(
+        description = "Receive deals with a title matching one of the comma-separated keywords"
+    )]
+    Watch,
+    #[command(description = "Stop receiving deals for one of the comma-separated keywords")]
+    Unwatch,
+    #[command(description = "Only receive deals up to this price. Use /maxprice off to disable")]
+    MaxPrice,
+    #[command(
+        description = "Pick the comma-separated feeds to receive deals from, like hot or vouchers"
+    )]
+    Feeds,
+    #[command(description = "Pick the Pepper country to receive deals from, like nl, de or uk")]
+    Instance,
+    #[command(
+        description = "Get an alert when a deal reaches this temperature. Use /hot off to disable"
+    )]
+    Hot,
+    #[command(
+        description = "Bundle your deals in an hourly or daily message. Use /digest off to disable"
+    )]
+    Digest,
+    #[command(
+        description = "Hold deals during these hours, like /quiet 23:00-07:00. Use /quiet off to disable"
+    )]
+    Quiet,
+    #[command(description = "Set your time zone for quiet hours, like /timezone Europe/Amsterdam")]
+    Timezone,
     #[command(description = "Get latest deals for specific keyword")]
     Deals,
     #[command(description = "List your current subscription")]
@@ -65,26 +252,69 @@ enum Command {
         description = "Admin - Broadcast to all subscribed users"
     )]
     AdminBroadcast,
+    #[command(
+        rename = "dead_letters",
+        description = "Admin - List the latest messages that failed sending"
+    )]
+    AdminDeadLetters,
+    #[command(
+        rename = "replay_dead_letters",
+        description = "Admin - Queue all messages that failed sending again"
+    )]
+    AdminReplayDeadLetters,
 }
 
 pub struct BotCommandService {
     pub bot: Bot,
-    pub redis_client: Client,
+    pub connections: RedisConnections,
 }
 
 impl BotCommandService {
-    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn start(&self, mut shutdown: Shutdown) -> Result<(), Box<dyn std::error::Error>> {
         info!("Started bot command service");
 
         let bot = self.bot.clone();
-        let redis_client = self.redis_client.clone();
+        let connections = self.connections.clone();
 
-        Command::repl(bot, move |bot, msg, cmd| {
-            info!("Received command: Command::{:?}", cmd);
-            // redis service clone is required, otherwise we lose the reference
-            Self::answer(bot, msg, cmd, redis_client.clone())
-        })
-        .await;
+        let handler =
+            dptree::entry()
+                .branch(
+                    Update::filter_message()
+                        .filter_command::<Command>()
+                        .endpoint(
+                            |bot: Bot,
+                             msg: Message,
+                             cmd: Command,
+                             connections: RedisConnections| async move {
+                                info!("Received command: Command::{:?}", cmd);
+                                Self::answer(bot, msg, cmd, connections).await
+                            },
+                        ),
+                )
+                .branch(Update::filter_callback_query().endpoint(
+                    |bot: Bot, query: CallbackQuery, connections: RedisConnections| async move {
+                        info!("Received callback: {:?}", query.data);
+                        Self::answer_callback(bot, query, connections).await
+                    },
+                ));
+
+        let mut dispatcher = Dispatcher::builder(bot, handler)
+            .dependencies(dptree::deps![connections])
+            .build();
+
+        // Stop polling for updates on shutdown, running handlers get to finish
+        let shutdown_token = dispatcher.shutdown_token();
+        tokio::spawn(async move {
+            shutdown.wait().await;
+
+            if let Ok(stopped) = shutdown_token.shutdown() {
+                let _ = tokio::time::timeout(get_grace_period(), stopped).await;
+            }
+        });
+
+        dispatcher.dispatch().await;
+
+        info!("Stopped bot command service");
 
         Ok(())
     }
@@ -102,6 +332,18 @@ impl BotCommandService {
         false
     }
 
+    async fn get_subscriber_instance(
+        connections: &RedisConnections,
+        chat_id: &str,
+    ) -> &'static Instance {
+        let name: Option<String> = connections
+            .subscribers
+            .get_preference(chat_id, Preference::Instance)
+            .await;
+
+        get_instance_or_default(name.as_deref())
+    }
+
     async fn send_message(
         bot: &Bot,
         chat_id: String,
@@ -123,47 +365,484 @@ impl BotCommandService {
         };
     }
 
+    async fn send_deals(
+        bot: &Bot,
+        chat_id: String,
+        instance: &Instance,
+        search: &str,
+        connections: &RedisConnections,
+    ) {
+        let pepper_request = PepperRequest::new(instance);
+        let (text, keyboard) = match pepper_request
+            .graphql(search, get_deals_search_limit())
+            .await
+        {
+            Some(deals) => {
+                let search = DealSearch {
+                    instance: instance.name.to_string(),
+                    search: search.to_string(),
+                    suggestions: deals.data.suggestions,
+                };
+
+                // Without a stored search the results are shown without buttons
+                let search_id = connections.deals.store_search(&search).await.ok();
+
+                Self::deals_page(&search, search_id, DealSort::Relevance, 0)
+            }
+            None => (
+                escape("Searching deals failed, please try again later."),
+                None,
+            ),
+        };
+
+        let mut request = bot
+            .send_message(chat_id, text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .disable_web_page_preview(true);
+
+        if let Some(keyboard) = keyboard {
+            request = request.reply_markup(keyboard);
+        }
+
+        if let Err(e) = request.await {
+            info!("Message failed sending {}", e);
+        }
+    }
+
+    // Pages through the stored results, so every page and sort order shows the same deals.
+    // The buttons only need the id of the stored search
+    fn deals_page(
+        search: &DealSearch,
+        search_id: Option<u64>,
+        sort: DealSort,
+        page: usize,
+    ) -> (String, Option<InlineKeyboardMarkup>) {
+        let instance = get_instance_or_default(Some(&search.instance));
+        let deals = &search.suggestions;
+        let search = &search.search;
+
+        if deals.deals.is_empty() {
+            return (escape(&format!("No deals found for {}", search)), None);
+        }
+
+        let mut items = deals.deals.clone();
+        match sort {
+            DealSort::Temperature => items.sort_by(|a, b| b.temperature.total_cmp(&a.temperature)),
+            DealSort::Price => items.sort_by(|a, b| a.price.total_cmp(&b.price)),
+            DealSort::Relevance => (),
+        }
+
+        let page_size = get_deals_page_size();
+        let pages = items.len().div_ceil(page_size);
+        let page = page.min(pages - 1);
+
+        // Only the first batch of a search can be paged and sorted through
+        let found = match deals.deal_count > items.len() as i64 {
+            true => format!(
+                "Showing the first {} of {} deals for {}",
+                items.len(),
+                deals.deal_count,
+                search
+            ),
+            false => format!("Found {} deals for {}", items.len(), search),
+        };
+
+        let mut text = format!(
+            "*{}*",
+            escape(&format!("{}, page {} of {}", found, page + 1, pages))
+        );
+
+        for item in items.iter().skip(page * page_size).take(page_size) {
+            text.push_str(&format!(
+                "\n*{}* \\- [{}]({}) {}°",
+                escape(&item.display_price),
+                escape(&item.title_slug.replace('-', " ")),
+                escape_link_url(&instance.deal_url(&item.title_slug, &item.thread_id)),
+                escape(&item.temperature.round().to_string())
+            ));
+        }
+
+        let keyboard =
+            search_id.map(|search_id| Self::deals_keyboard(search_id, sort, page, pages));
+
+        (text, keyboard)
+    }
+
+    fn deals_keyboard(
+        search_id: u64,
+        sort: DealSort,
+        page: usize,
+        pages: usize,
+    ) -> InlineKeyboardMarkup {
+        let data = |sort: DealSort, page: usize| {
+            format!("{}:{}:{}:{}", DEALS_CALLBACK, sort.value(), page, search_id)
+        };
+
+        let mut paging: Vec<InlineKeyboardButton> = vec![];
+        if page > 0 {
+            paging.push(InlineKeyboardButton::callback(
+                "Previous page",
+                data(sort, page - 1),
+            ));
+        }
+
+        if page + 1 < pages {
+            paging.push(InlineKeyboardButton::callback(
+                "Next page",
+                data(sort, page + 1),
+            ));
+        }
+
+        let sorting = vec![
+            InlineKeyboardButton::callback("Sort by temperature", data(DealSort::Temperature, 0)),
+            InlineKeyboardButton::callback("Sort by price", data(DealSort::Price, 0)),
+        ];
+
+        InlineKeyboardMarkup::new(vec![paging, sorting])
+    }
+
+    // Handles the buttons below the deal messages
+    async fn answer_callback(
+        bot: Bot,
+        query: CallbackQuery,
+        connections: RedisConnections,
+    ) -> Result<(), RequestError> {
+        let chat_id = match &query.message {
+            Some(message) => message.chat.id.to_string(),
+            None => query.from.id.to_string(),
+        };
+
+        let data = query.data.clone().unwrap_or_default();
+
+        match data.split_once(':') {
+            Some((action, category)) if action.eq(MUTE_CALLBACK) => {
+                let reply = match connections.is_available().await {
+                    true => {
+                        let mut excluded = connections
+                            .subscribers
+                            .get_preference_list(&chat_id, Preference::ExcludedCategories)
+                            .await;
+
+                        if !excluded.iter().any(|c| c.eq(category)) {
+                            excluded.push(category.to_string());
+                        }
+
+                        match connections
+                            .subscribers
+                            .set_preference_list(
+                                &chat_id,
+                                Preference::ExcludedCategories,
+                                &excluded,
+                            )
+                            .await
+                        {
+                            Ok(_) => format!("You will no longer receive deals from {}", category),
+                            Err(_) => {
+                                "Our service is currently down, please try again later.".to_string()
+                            }
+                        }
+                    }
+                    false => "Our service is currently down, please try again later.".to_string(),
+                };
+
+                bot.answer_callback_query(query.id).text(reply).await?;
+            }
+            Some((action, value)) if action.eq(CATEGORY_CALLBACK) => {
+                let reply = match connections.is_available().await {
+                    true => Self::pick_category(&bot, &query, &chat_id, value, &connections).await,
+                    false => {
+                        Some("Our service is currently down, please try again later.".to_string())
+                    }
+                };
+
+                match reply {
+                    Some(reply) => bot.answer_callback_query(query.id).text(reply).await?,
+                    None => bot.answer_callback_query(query.id).await?,
+                };
+            }
+            Some((action, value)) if action.eq(DEALS_CALLBACK) => {
+                let mut parts = value.splitn(3, ':');
+                let sort = parts.next().and_then(DealSort::from_value);
+                let page = parts.next().and_then(|p| p.parse::<usize>().ok());
+                let search_id = parts.next().and_then(|id| id.parse::<u64>().ok());
+
+                let search = match search_id {
+                    Some(search_id) => connections.deals.get_search(search_id).await,
+                    None => None,
+                };
+
+                match (&query.message, sort, page, search) {
+                    (Some(message), Some(sort), Some(page), Some(search)) => {
+                        bot.answer_callback_query(query.id.clone()).await?;
+
+                        let (text, keyboard) = Self::deals_page(&search, search_id, sort, page);
+
+                        let mut request = bot
+                            .edit_message_text(message.chat.id, message.id, text)
+                            .parse_mode(ParseMode::MarkdownV2)
+                            .disable_web_page_preview(true);
+
+                        if let Some(keyboard) = keyboard {
+                            request = request.reply_markup(keyboard);
+                        }
+
+                        if let Err(e) = request.await {
+                            info!("Updating the deals failed {}", e);
+                        }
+                    }
+                    _ => {
+                        bot.answer_callback_query(query.id)
+                            .text("This search expired, please use /deals again")
+                            .await?;
+                    }
+                }
+            }
+            Some((action, search)) if action.eq(SIMILAR_CALLBACK) => {
+                bot.answer_callback_query(query.id)
+                    .text(format!("Searching deals for {}", search))
+                    .await?;
+
+                let instance = Self::get_subscriber_instance(&connections, &chat_id).await;
+                Self::send_deals(&bot, chat_id, instance, search, &connections).await;
+            }
+            _ => {
+                bot.answer_callback_query(query.id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Toggles a category in the picker that /categories opens and edits the keyboard in
+    // place. Returns the text to show the subscriber when something went wrong
+    async fn pick_category(
+        bot: &Bot,
+        query: &CallbackQuery,
+        chat_id: &str,
+        value: &str,
+        connections: &RedisConnections,
+    ) -> Option<String> {
+        let message = query.message.as_ref()?;
+        let instance = Self::get_subscriber_instance(connections, chat_id).await;
+
+        let mut categories = connections
+            .subscribers
+            .get_preference_list(chat_id, Preference::Categories)
+            .await;
+
+        if value.eq(CATEGORY_ALL) {
+            categories.clear();
+        } else if !value.eq(CATEGORY_DONE) {
+            let category = value
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| instance.categories.get(i))?
+                .to_lowercase();
+
+            match categories.contains(&category) {
+                true => categories.retain(|c| !c.eq(&category)),
+                false => categories.push(category),
+            }
+        }
+
+        if connections
+            .subscribers
+            .add(chat_id, &categories, None)
+            .await
+            .is_err()
+        {
+            return Some("Our service is currently down, please try again later.".to_string());
+        }
+
+        let edited = match value.eq(CATEGORY_DONE) {
+            true => {
+                let text = match categories.is_empty() {
+                    true => "Signed up for all categories".to_string(),
+                    false => format!("Signed up for {}", categories.join(", ")),
+                };
+
+                bot.edit_message_text(message.chat.id, message.id, text)
+                    .await
+                    .map(|_| ())
+            }
+            false => bot
+                .edit_message_reply_markup(message.chat.id, message.id)
+                .reply_markup(Self::category_keyboard(instance.categories, &categories))
+                .await
+                .map(|_| ()),
+        };
+
+        if let Err(e) = edited {
+            info!("Updating the category picker failed {}", e);
+        }
+
+        None
+    }
+
+    // Two categories per row, the followed ones get a checkmark. Following none of them
+    // means following all
+    fn category_keyboard(categories: &[&str], selected: &[String]) -> InlineKeyboardMarkup {
+        let mut rows: Vec<Vec<InlineKeyboardButton>> = categories
+            .chunks(2)
+            .enumerate()
+            .map(|(row, chunk)| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(column, category)| {
+                        let label = match selected.contains(&category.to_lowercase()) {
+                            true => format!("✅ {}", category),
+                            false => category.to_string(),
+                        };
+
+                        InlineKeyboardButton::callback(
+                            label,
+                            format!("{}:{}", CATEGORY_CALLBACK, row * 2 + column),
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let all_label = match selected.is_empty() {
+            true => "✅ All",
+            false => "All",
+        };
+
+        rows.push(vec![
+            InlineKeyboardButton::callback(
+                all_label,
+                format!("{}:{}", CATEGORY_CALLBACK, CATEGORY_ALL),
+            ),
+            InlineKeyboardButton::callback(
+                "Done",
+                format!("{}:{}", CATEGORY_CALLBACK, CATEGORY_DONE),
+            ),
+        ]);
+
+        InlineKeyboardMarkup::new(rows)
+    }
+
+    // The categories of the instance including the ones Pepper added since the release
+    async fn get_categories(connections: &RedisConnections, instance: &Instance) -> Vec<String> {
+        let seen = connections.deals.get_seen_categories(instance.name).await;
+
+        get_live_categories(instance.categories, &seen)
+    }
+
+    // Matches the comma-separated categories of a command. When one of them could be
+    // several categories or none at all, the reply says so instead of guessing
+    fn match_categories(
+        message: &str,
+        seed: &[&str],
+        categories: &[String],
+    ) -> Result<Vec<String>, String> {
+        let categories: Vec<&str> = categories.iter().map(|c| c.as_str()).collect();
+        let mut matched: Vec<String> = vec![];
+        let mut ambiguous: Vec<String> = vec![];
+        let mut unknown: Vec<String> = vec![];
+
+        for possible_cat in message.split(',') {
+            let trimmed_category = possible_cat.trim();
+            if trimmed_category.is_empty() {
+                continue;
+            }
+
+            match match_category(trimmed_category, seed, &categories) {
+                CategoryMatch::Found(pepper_category) => {
+                    info!("Matched {} -> {:?}", trimmed_category, pepper_category);
+
+                    if !matched.contains(&pepper_category) {
+                        matched.push(pepper_category);
+                    }
+                }
+                CategoryMatch::Ambiguous(options) => {
+                    let options: Vec<String> = options.iter().map(|o| html::escape(o)).collect();
+
+                    ambiguous.push(format!(
+                        "\"{}\" could be {}",
+                        html::escape(trimmed_category),
+                        options.join(" or ")
+                    ));
+                }
+                CategoryMatch::NotFound => unknown.push(html::escape(trimmed_category)),
+            }
+        }
+
+        // A typo must not end up as an empty list, that would reset the filters
+        let mut errors: Vec<String> = vec![];
+        if !unknown.is_empty() {
+            errors.push(format!(
+                "Unknown categories: {}. Use /available_categories to see them all.",
+                unknown.join(", ")
+            ));
+        }
+        if !ambiguous.is_empty() {
+            errors.push(format!("{}. Which one did you mean?", ambiguous.join("\n")));
+        }
+
+        match errors.is_empty() {
+            true => Ok(matched),
+            false => Err(errors.join("\n\n")),
+        }
+    }
+
+    async fn send_category_picker(bot: &Bot, chat_id: String, connections: &RedisConnections) {
+        let instance = Self::get_subscriber_instance(connections, &chat_id).await;
+        let categories = connections
+            .subscribers
+            .get_preference_list(&chat_id, Preference::Categories)
+            .await;
+
+        if let Err(e) = bot
+            .send_message(
+                chat_id,
+                "Pick the categories you want to receive deals from",
+            )
+            .reply_markup(Self::category_keyboard(instance.categories, &categories))
+            .await
+        {
+            info!("Message failed sending {}", e);
+        }
+    }
+
     async fn answer(
         bot: Bot,
         msg: Message,
         cmd: Command,
-        redis_client: Client,
+        connections: RedisConnections,
     ) -> Result<(), RequestError> {
         match cmd {
             Command::AdminStopBot => {
-                if Self::is_admin(&msg.chat.id.to_string()) {
-                    if let Ok(mut con) = redis_client.get_connection() {
-                        let _ = set_config(&mut con, Config::OperationalKey, 0);
+                if Self::is_admin(&msg.chat.id.to_string()) && connections.is_available().await {
+                    let _ = connections.config.set(Config::OperationalKey, 0).await;
 
-                        Self::send_message(
-                            &bot,
-                            msg.chat.id.to_string(),
-                            "Stopped bot",
-                            Some(ParseMode::Html),
-                        )
-                        .await;
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Stopped bot",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
 
-                        return Ok(());
-                    }
+                    return Ok(());
                 }
 
                 Ok::<(), RequestError>(())
             }
             Command::AdminStartBot => {
-                if Self::is_admin(&msg.chat.id.to_string()) {
-                    if let Ok(mut con) = redis_client.get_connection() {
-                        let _ = set_config(&mut con, Config::OperationalKey, 1);
+                if Self::is_admin(&msg.chat.id.to_string()) && connections.is_available().await {
+                    let _ = connections.config.set(Config::OperationalKey, 1).await;
 
-                        Self::send_message(
-                            &bot,
-                            msg.chat.id.to_string(),
-                            "Started bot",
-                            Some(ParseMode::Html),
-                        )
-                        .await;
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Started bot",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
 
-                        return Ok(());
-                    }
+                    return Ok(());
                 }
 
                 Ok(())
@@ -172,7 +851,7 @@ impl BotCommandService {
                 if Self::is_admin(&msg.chat.id.to_string()) {
                     let message = msg.text().unwrap_or("");
 
-                    let subscribers = get_subscribers(redis_client).await;
+                    let subscribers = connections.subscribers.get_all().await;
 
                     if let Ok(subs) = subscribers {
                         for (chat_id, _) in subs {
@@ -189,67 +868,119 @@ impl BotCommandService {
 
                 Ok(())
             }
-            Command::Help => {
-                if let Ok(admin_chat) = env::var("ADMIN_CHAT_ID") {
-                    let cmds_string = Command::descriptions().to_string();
-
-                    let commands: Vec<&str> = cmds_string
-                        .split("\n")
-                        .filter(|l| {
-                            if admin_chat.eq(&msg.chat.id.to_string()) {
-                                return true;
-                            };
+            Command::AdminDeadLetters => {
+                if Self::is_admin(&msg.chat.id.to_string()) && connections.is_available().await {
+                    let (total, dead_letters) = connections.deals.get_dead_letters(10).await;
 
-                            l.to_lowercase().contains("admin") == false
+                    let lines: Vec<String> = dead_letters
+                        .iter()
+                        .map(|d| {
+                            format!(
+                                "{} - {} - {}",
+                                d.chat_id,
+                                html::escape(&d.message.payload.title),
+                                html::escape(&d.error)
+                            )
                         })
                         .collect();
 
                     Self::send_message(
                         &bot,
                         msg.chat.id.to_string(),
-                        commands.join("\n").as_str(),
+                        format!(
+                            "{} messages failed sending. Latest:\n\n{}",
+                            total,
+                            lines.join("\n")
+                        )
+                        .as_str(),
                         Some(ParseMode::Html),
                     )
                     .await;
-
-                    return Ok(());
                 }
 
                 Ok(())
             }
-            Command::Start => {
-                if let Ok(mut con) = redis_client.get_connection() {
-                    // Set correct database first
-                    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-                        .arg(Database::SUBSCRIBER as u8)
-                        .query(&mut con);
-
-                    let _: Result<(), redis::RedisError> = redis::cmd("SET")
-                        .arg(msg.chat.id.to_string())
-                        .arg(1)
-                        .query(&mut con);
-                }
+            Command::AdminReplayDeadLetters => {
+                if Self::is_admin(&msg.chat.id.to_string()) && connections.is_available().await {
+                    let dead_letters = connections.deals.take_dead_letters().await;
+                    let mut replayed = 0;
 
-                Self::send_message(
-                    &bot,
-                    msg.chat.id.to_string(),
-                    "Signup was successful. You will now receive new updates from Pepper",
-                    Some(ParseMode::Html),
-                )
+                    // Only the chat that missed the message gets it again
+                    for dead_letter in dead_letters {
+                        let mut message = dead_letter.message;
+                        message.recipients = Some(vec![dead_letter.chat_id]);
+
+                        match connections.deals.requeue_message(&message).await {
+                            Ok(_) => replayed += 1,
+                            Err(e) => info!("Replaying {} failed {:?}", message.id, e),
+                        }
+                    }
+
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        format!("Queued {} messages again", replayed).as_str(),
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Help => {
+                if let Ok(admin_chat) = env::var("ADMIN_CHAT_ID") {
+                    let cmds_string = Command::descriptions().to_string();
+
+                    let commands: Vec<&str> = cmds_string
+                        .split("\n")
+                        .filter(|l| {
+                            if admin_chat.eq(&msg.chat.id.to_string()) {
+                                return true;
+                            };
+
+                            !l.to_lowercase().contains("admin")
+                        })
+                        .collect();
+
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        commands.join("\n").as_str(),
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+
+                    return Ok(());
+                }
+
+                Ok(())
+            }
+            Command::Start => {
+                if connections.is_available().await {
+                    let language = msg.from().and_then(|user| user.language_code.clone());
+
+                    let _ = connections
+                        .subscribers
+                        .add(&msg.chat.id.to_string(), &[], language.as_deref())
+                        .await;
+                }
+
+                Self::send_message(
+                    &bot,
+                    msg.chat.id.to_string(),
+                    "Signup was successful. You will now receive new updates from Pepper",
+                    Some(ParseMode::Html),
+                )
                 .await;
 
                 Ok(())
             }
             Command::Stop => {
-                if let Ok(mut con) = redis_client.get_connection() {
-                    // Set correct database first
-                    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-                        .arg(Database::SUBSCRIBER as u8)
-                        .query(&mut con);
-
-                    let _deleted_amount: Result<i32, redis::RedisError> = redis::cmd("DEL")
-                        .arg(msg.chat.id.to_string())
-                        .query(&mut con);
+                if connections.is_available().await {
+                    let _ = connections
+                        .subscribers
+                        .remove(&msg.chat.id.to_string())
+                        .await;
 
                     Self::send_message(
                         &bot,
@@ -262,36 +993,87 @@ impl BotCommandService {
                 Ok(())
             }
             Command::Status => {
-                if let Ok(mut con) = redis_client.get_connection() {
-                    // Set correct database first
-                    let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-                        .arg(Database::SUBSCRIBER as u8)
-                        .query(&mut con);
-
-                    let user: Result<String, redis::RedisError> = redis::cmd("GET")
-                        .arg(msg.chat.id.to_string())
-                        .query(&mut con);
-
-                    match user {
-                        Ok(user) => {
-                            let message_addition: &str = match user.as_str() {
-                                "1" => "all categories",
-                                _ => user.as_str(),
+                if connections.is_available().await {
+                    match connections.subscribers.get(&msg.chat.id.to_string()).await {
+                        Some(subscriber) => {
+                            let message_addition = match &subscriber.categories {
+                                Some(categories) => html::escape(&categories.join(", ")),
+                                None => "all categories".to_string(),
+                            };
+
+                            let keywords_addition = match subscriber.keywords.is_empty() {
+                                true => "".to_string(),
+                                false => {
+                                    format!(
+                                        " and watching {}",
+                                        html::escape(&subscriber.keywords.join(", "))
+                                    )
+                                }
+                            };
+
+                            let max_price_addition = match subscriber.max_price {
+                                Some(p) => format!(". Deals above {} are skipped", p),
+                                None => "".to_string(),
+                            };
+
+                            let hot_addition = match subscriber.hot_threshold {
+                                Some(t) => format!(". You get an alert for deals reaching {}°", t),
+                                None => "".to_string(),
+                            };
+
+                            let digest_addition = match subscriber.mode {
+                                Mode::Digest(d) => {
+                                    format!(". Deals are bundled in your {} digest", d.value())
+                                }
+                                Mode::Instant => "".to_string(),
                             };
 
+                            let quiet_addition = match subscriber.quiet_hours {
+                                Some(q) => format!(
+                                    ". Deals are held between {} ({})",
+                                    q.value().replace('-', " and "),
+                                    subscriber.timezone.unwrap_or(DEFAULT_TIMEZONE).name()
+                                ),
+                                None => "".to_string(),
+                            };
+
+                            let excluded_addition = match subscriber.excluded_categories.is_empty()
+                            {
+                                true => "".to_string(),
+                                false => format!(
+                                    ". Excluded categories: {}",
+                                    html::escape(&subscriber.excluded_categories.join(", "))
+                                ),
+                            };
+
+                            let feeds = match subscriber.feeds.is_empty() {
+                                true => vec![DEFAULT_FEED.to_string()],
+                                false => subscriber.feeds.clone(),
+                            };
+
+                            let instance = get_instance_or_default(subscriber.instance.as_deref());
+
                             Self::send_message(
                                 &bot,
                                 msg.chat.id.to_string(),
                                 format!(
-                                    "You are subscribed to Pepperbot. You are following {}",
-                                    message_addition
+                                    "You are subscribed to Pepperbot. You are following {}{} from the {} feed of {}{}{}{}{}{}",
+                                    message_addition,
+                                    keywords_addition,
+                                    feeds.join(", "),
+                                    instance.base,
+                                    max_price_addition,
+                                    hot_addition,
+                                    excluded_addition,
+                                    digest_addition,
+                                    quiet_addition
                                 )
                                 .as_str(),
                                 Some(ParseMode::Html),
                             )
                             .await;
                         }
-                        _ => {
+                        None => {
                             Self::send_message(
                                 &bot,
                                 msg.chat.id.to_string(),
@@ -306,33 +1088,47 @@ impl BotCommandService {
                 Ok(())
             }
             Command::Categories => {
-                if let Ok(mut con) = redis_client.get_connection() {
+                if connections.is_available().await {
                     if let Some(text) = msg.text() {
-                        // Set correct database first
-                        let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-                            .arg(Database::SUBSCRIBER as u8)
-                            .query(&mut con);
-
                         let message = text.replace("/categories", "");
 
-                        let mut passed_categories: Vec<String> = vec![];
-                        for possible_cat in message.split(",") {
-                            let trimmed_category = possible_cat.trim();
+                        if message.trim().is_empty() {
+                            Self::send_category_picker(&bot, msg.chat.id.to_string(), &connections)
+                                .await;
 
-                            if trimmed_category.len() > 0 {
-                                if let Some(pepper_category) = match_category(trimmed_category) {
-                                    info!("Matched {} -> {:?}", trimmed_category, pepper_category);
-                                    passed_categories.push(pepper_category);
-                                }
-                            }
+                            return Ok(());
                         }
 
+                        let instance =
+                            Self::get_subscriber_instance(&connections, &msg.chat.id.to_string())
+                                .await;
+                        let categories = Self::get_categories(&connections, instance).await;
+
+                        let passed_categories = match Self::match_categories(
+                            &message,
+                            instance.categories,
+                            &categories,
+                        ) {
+                            Ok(categories) => categories,
+                            Err(reply) => {
+                                Self::send_message(
+                                    &bot,
+                                    msg.chat.id.to_string(),
+                                    &reply,
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+
+                                return Ok(());
+                            }
+                        };
+
                         // If there are no categories found or set, reset filters
-                        if passed_categories.len() == 0 {
-                            let _: Result<(), redis::RedisError> = redis::cmd("SET")
-                                .arg(msg.chat.id.to_string())
-                                .arg(1)
-                                .query(&mut con);
+                        if passed_categories.is_empty() {
+                            let _ = connections
+                                .subscribers
+                                .add(&msg.chat.id.to_string(), &[], None)
+                                .await;
 
                             Self::send_message(
                                 &bot,
@@ -344,15 +1140,19 @@ impl BotCommandService {
 
                         // If there are filters found, set the filters for this user
                         } else {
-                            let _: Result<(), redis::RedisError> = redis::cmd("SET")
-                                .arg(msg.chat.id.to_string())
-                                .arg(passed_categories.join(","))
-                                .query(&mut con);
+                            let _ = connections
+                                .subscribers
+                                .add(&msg.chat.id.to_string(), &passed_categories, None)
+                                .await;
 
                             Self::send_message(
                                 &bot,
                                 msg.chat.id.to_string(),
-                                format!("Signed up for {}", passed_categories.join(", ")).as_str(),
+                                format!(
+                                    "Signed up for {}",
+                                    html::escape(&passed_categories.join(", "))
+                                )
+                                .as_str(),
                                 Some(ParseMode::Html),
                             )
                             .await;
@@ -379,13 +1179,84 @@ impl BotCommandService {
 
                 Ok(())
             }
+            Command::Exclude => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/exclude", "");
+                        let instance = Self::get_subscriber_instance(&connections, &chat_id).await;
+                        let categories = Self::get_categories(&connections, instance).await;
+
+                        let excluded_categories = match Self::match_categories(
+                            &message,
+                            instance.categories,
+                            &categories,
+                        ) {
+                            Ok(categories) => categories,
+                            Err(reply) => {
+                                Self::send_message(&bot, chat_id, &reply, Some(ParseMode::Html))
+                                    .await;
+
+                                return Ok(());
+                            }
+                        };
+
+                        let reply = match connections
+                            .subscribers
+                            .set_preference_list(
+                                &chat_id,
+                                Preference::ExcludedCategories,
+                                &excluded_categories,
+                            )
+                            .await
+                        {
+                            Ok(_) if excluded_categories.is_empty() => {
+                                "No categories passed, you receive deals from all categories again"
+                                    .to_string()
+                            }
+                            Ok(_) => format!(
+                                "You will no longer receive deals from {}",
+                                html::escape(&excluded_categories.join(", "))
+                            ),
+                            Err(_) => {
+                                "Our service is currently down, please try again later.".to_string()
+                            }
+                        };
+
+                        Self::send_message(&bot, chat_id, reply.as_str(), Some(ParseMode::Html))
+                            .await;
+                    } else {
+                        Self::send_message(
+                            &bot,
+                            msg.chat.id.to_string(),
+                            "Something went wrong with reading your message, please try again.",
+                            Some(ParseMode::Html),
+                        )
+                        .await;
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
             Command::AvailableCategories => {
+                let instance =
+                    Self::get_subscriber_instance(&connections, &msg.chat.id.to_string()).await;
+                let categories = Self::get_categories(&connections, instance).await;
+
                 Self::send_message(
                     &bot,
                     msg.chat.id.to_string(),
                     format!(
                         "The following categories are available for signups: \n\n{}",
-                        CATEGORIES.join("\n")
+                        html::escape(&categories.join("\n"))
                     )
                     .as_str(),
                     Some(ParseMode::Html),
@@ -394,40 +1265,540 @@ impl BotCommandService {
 
                 Ok(())
             }
-            Command::Deals => {
-                if let Some(text) = msg.text() {
-                    let message = text.replace("/deals ", "");
-                    let pepper_request = PepperRequest::new();
-                    if let Some(deals) = pepper_request.graphql(&message).await {
-                        Self::send_message(
-                            &bot,
-                            msg.chat.id.to_string(),
-                            &format!(
-                                "Found {} deals for {}",
-                                deals.data.suggestions.deal_count, message
+            Command::Watch | Command::Unwatch => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let is_watch = matches!(cmd, Command::Watch);
+
+                        let message = match is_watch {
+                            true => text.replace("/watch", ""),
+                            false => text.replace("/unwatch", ""),
+                        };
+
+                        let passed_keywords: Vec<String> = message
+                            .split(',')
+                            .map(|k| k.trim().to_lowercase())
+                            .filter(|k| !k.is_empty())
+                            .collect();
+
+                        if passed_keywords.is_empty() {
+                            Self::send_message(
+                                &bot,
+                                chat_id,
+                                "No keywords passed, use for example /watch airpods, lego",
+                                Some(ParseMode::Html),
+                            )
+                            .await;
+
+                            return Ok(());
+                        }
+
+                        let mut keywords = connections
+                            .subscribers
+                            .get_preference_list(&chat_id, Preference::Keywords)
+                            .await;
+
+                        for keyword in &passed_keywords {
+                            if is_watch && !keywords.contains(keyword) {
+                                keywords.push(keyword.clone());
+                            }
+
+                            if !is_watch {
+                                keywords.retain(|k| k != keyword);
+                            }
+                        }
+
+                        let _ = connections
+                            .subscribers
+                            .set_preference_list(&chat_id, Preference::Keywords, &keywords)
+                            .await;
+
+                        let reply = match (is_watch, keywords.is_empty()) {
+                            (true, _) => format!("Watching {}", html::escape(&keywords.join(", "))),
+                            (false, true) => "You are no longer watching any keywords".to_string(),
+                            (false, false) => {
+                                format!("Still watching {}", html::escape(&keywords.join(", ")))
+                            }
+                        };
+
+                        Self::send_message(&bot, chat_id, reply.as_str(), Some(ParseMode::Html))
+                            .await;
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::MaxPrice => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/maxprice", "");
+                        let message = message.trim();
+
+                        if message.is_empty() || message.eq("off") {
+                            let _ = connections
+                                .subscribers
+                                .set_preference::<f64>(&chat_id, Preference::MaxPrice, None)
+                                .await;
+
+                            Self::send_message(
+                                &bot,
+                                chat_id,
+                                "Disabled your maximum price, you will receive deals of any price",
+                                Some(ParseMode::Html),
+                            )
+                            .await;
+
+                            return Ok(());
+                        }
+
+                        match parse_price(message) {
+                            Some(max_price) => {
+                                let instance =
+                                    Self::get_subscriber_instance(&connections, &chat_id).await;
+
+                                let _ = connections
+                                    .subscribers
+                                    .set_preference(&chat_id, Preference::MaxPrice, Some(max_price))
+                                    .await;
+
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    format!(
+                                        "You will only receive deals up to {}",
+                                        instance.format_price(max_price)
+                                    )
+                                    .as_str(),
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                            None => {
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    "Could not read that amount, use for example /maxprice 50",
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Feeds => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/feeds", "");
+                        let instance = Self::get_subscriber_instance(&connections, &chat_id).await;
+                        let available_feeds = get_feed_names(instance);
+
+                        let passed_feeds: Vec<String> = message
+                            .split(',')
+                            .map(|f| f.trim().to_lowercase())
+                            .filter(|f| available_feeds.contains(f))
+                            .collect();
+
+                        // Without any known feed, go back to the default feed
+                        let _ = connections
+                            .subscribers
+                            .set_preference_list(&chat_id, Preference::Feeds, &passed_feeds)
+                            .await;
+
+                        let reply = match passed_feeds.is_empty() {
+                            true => format!(
+                                "No known feeds passed, you will receive deals from the {} feed. Available feeds: {}",
+                                DEFAULT_FEED,
+                                available_feeds.join(", ")
                             ),
-                            Some(ParseMode::Html),
-                        )
-                        .await;
+                            false => format!("Receiving deals from {}", passed_feeds.join(", ")),
+                        };
+
+                        Self::send_message(&bot, chat_id, reply.as_str(), Some(ParseMode::Html))
+                            .await;
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Instance => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/instance", "");
+
+                        match get_instance(&message) {
+                            Some(instance) => {
+                                let _ = connections
+                                    .subscribers
+                                    .set_preference(
+                                        &chat_id,
+                                        Preference::Instance,
+                                        Some(instance.name),
+                                    )
+                                    .await;
 
-                        for item in deals.data.suggestions.deals {
-                            let sanitized_title = item.title_slug.replace("-", " ");
+                                // Category names differ per country, so the old filters no longer apply
+                                for preference in
+                                    [Preference::Categories, Preference::ExcludedCategories]
+                                {
+                                    let _ = connections
+                                        .subscribers
+                                        .set_preference::<String>(&chat_id, preference, None)
+                                        .await;
+                                }
+
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    format!(
+                                        "You will now receive deals from {}. Your category filters and excluded categories were reset",
+                                        instance.base
+                                    )
+                                    .as_str(),
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                            None => {
+                                let available_instances: Vec<String> = INSTANCES
+                                    .iter()
+                                    .map(|i| format!("{} - {}", i.name, i.base))
+                                    .collect();
+
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    format!(
+                                        "Unknown instance, the following instances are available: \n\n{}",
+                                        available_instances.join("\n")
+                                    )
+                                    .as_str(),
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Hot => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/hot", "");
+                        let message = message.trim();
+
+                        if message.is_empty() || message.eq("off") {
+                            let _ = connections
+                                .subscribers
+                                .set_preference::<f64>(&chat_id, Preference::HotThreshold, None)
+                                .await;
 
                             Self::send_message(
                                 &bot,
-                                msg.chat.id.to_string(),
-                                &format!(
-                                    "*{}* \\- [{}](https://nl.pepper.com/aanbiedingen/{}-{})",
-                                    item.display_price,
-                                    sanitized_title,
-                                    item.title_slug,
-                                    item.thread_id
-                                ),
-                                Some(ParseMode::MarkdownV2),
+                                chat_id,
+                                "Disabled your temperature alerts",
+                                Some(ParseMode::Html),
                             )
                             .await;
+
+                            return Ok(());
+                        }
+
+                        match message.trim_end_matches('°').parse::<f64>() {
+                            Ok(threshold) => {
+                                let _ = connections
+                                    .subscribers
+                                    .set_preference(
+                                        &chat_id,
+                                        Preference::HotThreshold,
+                                        Some(threshold),
+                                    )
+                                    .await;
+
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    format!(
+                                        "You will get an alert when a deal reaches {}°",
+                                        threshold
+                                    )
+                                    .as_str(),
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                            Err(_) => {
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    "Could not read that temperature, use for example /hot 500",
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
                         }
                     }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Digest => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/digest", "");
+                        let message = message.trim();
+
+                        if message.is_empty() || message.eq("off") {
+                            let _ = connections
+                                .subscribers
+                                .set_preference::<String>(&chat_id, Preference::Mode, None)
+                                .await;
+
+                            Self::send_message(
+                                &bot,
+                                chat_id,
+                                "Disabled your digest, deals are sent as soon as they are posted",
+                                Some(ParseMode::Html),
+                            )
+                            .await;
+
+                            return Ok(());
+                        }
+
+                        match Digest::from_value(message) {
+                            Some(digest) => {
+                                let _ = connections
+                                    .subscribers
+                                    .set_preference(
+                                        &chat_id,
+                                        Preference::Mode,
+                                        Some(digest.value()),
+                                    )
+                                    .await;
+
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    format!(
+                                        "You will receive deals bundled in your {} digest",
+                                        digest.value()
+                                    )
+                                    .as_str(),
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                            None => {
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    "Unknown digest, use /digest hourly, /digest daily or /digest off",
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Quiet => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/quiet", "");
+                        let message = message.trim();
+
+                        if message.is_empty() || message.eq("off") {
+                            let _ = connections
+                                .subscribers
+                                .set_preference::<String>(&chat_id, Preference::QuietHours, None)
+                                .await;
+
+                            Self::send_message(
+                                &bot,
+                                chat_id,
+                                "Disabled your quiet hours, deals are sent at any time",
+                                Some(ParseMode::Html),
+                            )
+                            .await;
+
+                            return Ok(());
+                        }
+
+                        match QuietHours::from_value(message) {
+                            Some(quiet_hours) => {
+                                let timezone: Option<String> = connections
+                                    .subscribers
+                                    .get_preference(&chat_id, Preference::Timezone)
+                                    .await;
+
+                                let _ = connections
+                                    .subscribers
+                                    .set_preference(
+                                        &chat_id,
+                                        Preference::QuietHours,
+                                        Some(quiet_hours.value()),
+                                    )
+                                    .await;
+
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    format!(
+                                        "Deals posted between {} are held until your quiet hours end. Times are in {}, use /timezone to change it",
+                                        quiet_hours.value().replace('-', " and "),
+                                        timezone.unwrap_or(DEFAULT_TIMEZONE.name().to_string())
+                                    )
+                                    .as_str(),
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                            None => {
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    "Could not read those hours, use for example /quiet 23:00-07:00",
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Timezone => {
+                if connections.is_available().await {
+                    if let Some(text) = msg.text() {
+                        let chat_id = msg.chat.id.to_string();
+                        let message = text.replace("/timezone", "");
+
+                        match parse_timezone(&message) {
+                            Some(timezone) => {
+                                let _ = connections
+                                    .subscribers
+                                    .set_preference(
+                                        &chat_id,
+                                        Preference::Timezone,
+                                        Some(timezone.name()),
+                                    )
+                                    .await;
+
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    format!("Your time zone is now {}", timezone.name()).as_str(),
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                            None => {
+                                Self::send_message(
+                                    &bot,
+                                    chat_id,
+                                    "Unknown time zone, use for example /timezone Europe/Amsterdam",
+                                    Some(ParseMode::Html),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                } else {
+                    Self::send_message(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        "Our service is currently down, please try again later.",
+                        Some(ParseMode::Html),
+                    )
+                    .await;
+                }
+
+                Ok(())
+            }
+            Command::Deals => {
+                if let Some(text) = msg.text() {
+                    let message = text.replace("/deals ", "");
+                    let instance =
+                        Self::get_subscriber_instance(&connections, &msg.chat.id.to_string()).await;
+
+                    Self::send_deals(
+                        &bot,
+                        msg.chat.id.to_string(),
+                        instance,
+                        &message,
+                        &connections,
+                    )
+                    .await;
                 }
                 Ok(())
             }
@@ -454,10 +1825,25 @@ impl BotCommandService {
 #[derive(Clone)]
 pub struct BotMessageService {
     pub bot: Bot,
+    pub rate_limiter: Arc<RateLimiter>,
 }
 
 impl BotMessageService {
+    pub fn new(bot: Bot, config: ConfigStore) -> BotMessageService {
+        BotMessageService {
+            bot,
+            rate_limiter: Arc::new(RateLimiter::from_env(config)),
+        }
+    }
+
     pub async fn send_message(&self, chat_id: String, message: String) -> Result<(), BotError> {
+        self.with_retries(&chat_id, "message", || {
+            self.try_send_message(chat_id.clone(), message.clone())
+        })
+        .await
+    }
+
+    async fn try_send_message(&self, chat_id: String, message: String) -> Result<(), BotError> {
         match self
             .bot
             .send_message(chat_id, message)
@@ -467,8 +1853,545 @@ impl BotMessageService {
             Ok(_) => Ok(()),
             Err(e) => {
                 info!("Message failed sending {}", e);
-                Err(BotError::SendMessageError(e))
+                Err(BotError::from(e))
+            }
+        }
+    }
+
+    pub async fn send_deal(&self, chat_id: String, message: &DealMessage) -> Result<(), BotError> {
+        self.with_retries(&chat_id, &message.id, || {
+            self.try_send_deal(chat_id.clone(), message)
+        })
+        .await
+    }
+
+    // Retries transient failures with an exponential backoff, or as long as Telegram asks
+    async fn with_retries<F, Fut>(
+        &self,
+        chat_id: &str,
+        description: &str,
+        send: F,
+    ) -> Result<(), BotError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<(), BotError>>,
+    {
+        let max_attempts = get_send_attempts();
+        let mut attempt = 1;
+
+        loop {
+            self.rate_limiter.acquire(chat_id).await;
+
+            match send().await {
+                Err(e) if e.is_transient() && attempt < max_attempts => {
+                    let retry_after = e.retry_after();
+                    let wait = retry_after.unwrap_or_else(|| get_backoff(attempt));
+
+                    info!(
+                        "Sending {} to {} failed (attempt {}/{}), retrying in {:?}",
+                        description, chat_id, attempt, max_attempts, wait
+                    );
+
+                    // The rate limiter holds back the next attempt when Telegram asked for it
+                    match retry_after {
+                        Some(_) => self.rate_limiter.pause(chat_id, wait).await,
+                        None => tokio::time::sleep(wait).await,
+                    }
+
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Sends the deal as a photo with its details as caption, deals without a usable
+    // image fall back to a text message
+    async fn try_send_deal(&self, chat_id: String, message: &DealMessage) -> Result<(), BotError> {
+        let deal = &message.payload;
+        let caption = Self::format_deal(message);
+        let keyboard = Self::deal_keyboard(deal);
+
+        if let Some(image) = deal
+            .image
+            .as_ref()
+            .and_then(|i| reqwest::Url::parse(i).ok())
+        {
+            let result = self
+                .bot
+                .send_photo(chat_id.clone(), InputFile::url(image))
+                .caption(caption.clone())
+                .parse_mode(ParseMode::MarkdownV2)
+                .reply_markup(keyboard.clone())
+                .await;
+
+            match result {
+                Ok(_) => return Ok(()),
+                // Telegram could not fetch or process the image, the text still gets through
+                Err(RequestError::Api(
+                    e @ (ApiError::WrongFileIdOrUrl
+                    | ApiError::FailedToGetUrlContent
+                    | ApiError::WrongHttpUrl
+                    | ApiError::ImageProcessFailed
+                    | ApiError::PhotoAsInputFileRequired
+                    | ApiError::RequestEntityTooLarge
+                    | ApiError::Unknown(_)),
+                )) => info!("Photo failed sending {}, sending text", e),
+                Err(e) => {
+                    info!("Photo failed sending {}", e);
+                    return Err(BotError::from(e));
+                }
+            }
+        }
+
+        match self
+            .bot
+            .send_message(chat_id, caption)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_markup(keyboard)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                info!("Message failed sending {}", e);
+                Err(BotError::from(e))
+            }
+        }
+    }
+
+    // MarkdownV2 caption with the title, prices, merchant and the start of the description
+    pub fn format_deal(message: &DealMessage) -> String {
+        let deal = &message.payload;
+        let instance = get_instance_or_default(Some(&message.instance));
+
+        let mut caption = match &message.alert {
+            Some(alert) => format!("🔥 {}° \\- ", escape(&alert.current.round().to_string())),
+            None => "".to_string(),
+        };
+
+        caption.push_str(&format!(
+            "*[{}]({})*",
+            escape(&deal.title),
+            escape_link_url(&deal.link)
+        ));
+
+        let mut details: Vec<String> = vec![];
+
+        if let Some(price) = deal.price {
+            let mut price_detail = escape(&instance.format_price(price));
+
+            if let Some(original_price) = deal.original_price {
+                price_detail.push_str(&format!(
+                    " ~{}~",
+                    escape(&instance.format_price(original_price))
+                ));
+            }
+
+            details.push(price_detail);
+        }
+
+        if let Some(merchant) = &deal.merchant {
+            details.push(format!("_{}_", escape(merchant)));
+        }
+
+        if !details.is_empty() {
+            caption.push_str(&format!("\n{}", details.join(" \\- ")));
+        }
+
+        if let Some(description) = &deal.description {
+            caption.push_str(&format!(
+                "\n\n{}",
+                escape(&Self::truncate(description, 300))
+            ));
+        }
+
+        caption
+    }
+
+    // A digest of a single deal is just that deal, larger ones are combined into as few
+    // messages as fit
+    pub async fn send_digest(
+        &self,
+        chat_id: String,
+        messages: &[DealMessage],
+    ) -> Result<(), BotError> {
+        if let [message] = messages {
+            return self.send_deal(chat_id, message).await;
+        }
+
+        for text in Self::format_digest(messages) {
+            self.send_message(chat_id.clone(), text).await?;
+        }
+
+        Ok(())
+    }
+
+    // MarkdownV2 messages with the deals grouped by category, a deal that was buffered
+    // more than once (like from several feeds) is listed once
+    pub fn format_digest(messages: &[DealMessage]) -> Vec<String> {
+        let mut links: Vec<&str> = vec![];
+        let mut categories: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+
+        for message in messages {
+            let deal = &message.payload;
+            if links.contains(&deal.link.as_str()) {
+                continue;
+            }
+            links.push(&deal.link);
+
+            let instance = get_instance_or_default(Some(&message.instance));
+            let mut line = format!(
+                "• [{}]({})",
+                escape(&deal.title),
+                escape_link_url(&deal.link)
+            );
+
+            if let Some(price) = deal.price {
+                line.push_str(&format!(" \\- {}", escape(&instance.format_price(price))));
+            }
+
+            if let Some(alert) = &message.alert {
+                line.push_str(&format!(
+                    " 🔥 {}°",
+                    escape(&alert.current.round().to_string())
+                ));
+            }
+
+            let category = match deal.categories.first() {
+                Some(category) => category.as_str(),
+                None => "other",
+            };
+
+            categories.entry(category).or_default().push(line);
+        }
+
+        let mut texts: Vec<String> = vec![];
+        let mut text = format!("*{} new deals*", links.len());
+
+        for (category, lines) in categories {
+            Self::append_part(
+                &mut texts,
+                &mut text,
+                &format!("\n\n*{}*", escape(category)),
+            );
+
+            for line in lines {
+                Self::append_part(&mut texts, &mut text, &format!("\n{}", line));
+            }
+        }
+
+        texts.push(text);
+        texts
+    }
+
+    // Telegram messages are limited to 4096 characters, parts that don't fit anymore
+    // start a new message
+    fn append_part(texts: &mut Vec<String>, text: &mut String, part: &str) {
+        if text.chars().count() + part.chars().count() > 4000 {
+            texts.push(std::mem::take(text));
+            text.push_str(part.trim_start());
+        } else {
+            text.push_str(part);
+        }
+    }
+
+    fn deal_keyboard(deal: &Deal) -> InlineKeyboardMarkup {
+        let mut buttons: Vec<InlineKeyboardButton> = vec![];
+
+        if let Ok(url) = reqwest::Url::parse(&deal.link) {
+            buttons.push(InlineKeyboardButton::url("Open deal", url));
+        }
+
+        if let Some(category) = deal.categories.first() {
+            let mute_data = format!("{}:{}", MUTE_CALLBACK, category);
+
+            if mute_data.len() <= MAX_CALLBACK_DATA_BYTES {
+                buttons.push(InlineKeyboardButton::callback(
+                    "Mute this category",
+                    mute_data,
+                ));
+            }
+        }
+
+        let search = Self::similar_search(&deal.title);
+        if !search.is_empty() {
+            buttons.push(InlineKeyboardButton::callback(
+                "Similar deals",
+                format!("{}:{}", SIMILAR_CALLBACK, search),
+            ));
+        }
+
+        InlineKeyboardMarkup::new(vec![buttons])
+    }
+
+    // The first words of the title without prices or model numbers make a decent search
+    fn similar_search(title: &str) -> String {
+        let max_bytes = MAX_CALLBACK_DATA_BYTES - SIMILAR_CALLBACK.len() - 1;
+        let mut search = String::new();
+
+        for word in title
+            .split_whitespace()
+            .filter(|w| w.len() > 2 && w.chars().all(|c| c.is_alphabetic()))
+            .take(3)
+        {
+            let word = word.to_lowercase();
+
+            if search.len() + word.len() + 1 > max_bytes {
+                break;
             }
+
+            if !search.is_empty() {
+                search.push(' ');
+            }
+
+            search.push_str(&word);
+        }
+
+        search
+    }
+
+    fn truncate(text: &str, max_chars: usize) -> String {
+        match text.char_indices().nth(max_chars) {
+            Some((index, _)) => format!("{}…", text[..index].trim_end()),
+            None => text.to_string(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deals_page_pages_through_the_stored_results() {
+        let deals = (1..=12)
+            .map(|i| crate::structs::graphql_response::Deal {
+                thread_id: i.to_string(),
+                title_slug: format!("deal-{}", i),
+                price: (20 - i) as f64,
+                display_price: format!("€{}", 20 - i),
+                temperature: i as f64,
+                ..Default::default()
+            })
+            .collect();
+        let search = DealSearch {
+            instance: "nl".to_string(),
+            search: "switch".to_string(),
+            suggestions: crate::structs::graphql_response::Suggestions {
+                deal_count: 40,
+                deals,
+            },
+        };
+
+        let (text, keyboard) =
+            BotCommandService::deals_page(&search, Some(7), DealSort::Relevance, 1);
+        assert!(text.starts_with("*Showing the first 12 of 40 deals for switch, page 2 of 2*"));
+        assert!(text.contains("[deal 11]") && text.contains("[deal 12]"));
+        assert!(!text.contains("[deal 10]"));
+        assert_eq!(keyboard.unwrap().inline_keyboard[0].len(), 1);
+
+        // Sorting only reorders the same results
+        let (text, _) = BotCommandService::deals_page(&search, Some(7), DealSort::Price, 0);
+        assert!(text.contains("\n*€8* \\- [deal 12]"));
+
+        let (_, keyboard) = BotCommandService::deals_page(&search, None, DealSort::Relevance, 0);
+        assert!(keyboard.is_none());
+    }
+
+    #[test]
+    fn match_categories_reports_unknown_names() {
+        let seed = get_instance("nl").unwrap().categories;
+        let categories: Vec<String> = seed.iter().map(|c| c.to_string()).collect();
+
+        assert_eq!(
+            BotCommandService::match_categories(" gaming, Elektronica", seed, &categories),
+            Ok(vec!["gaming".to_string(), "elektronica".to_string()])
+        );
+        assert_eq!(
+            BotCommandService::match_categories(" ", seed, &categories),
+            Ok(vec![])
+        );
+
+        let reply = BotCommandService::match_categories("gaming, elektronka", seed, &categories);
+        assert!(reply.is_err_and(|r| r.contains("Unknown categories: elektronka.")));
+    }
+
+    #[test]
+    fn bot_error_classifies_request_errors() {
+        let network = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let json = serde_json::from_str::<u8>("{").unwrap_err();
+        let gone = [
+            ApiError::BotBlocked,
+            ApiError::ChatNotFound,
+            ApiError::UserDeactivated,
+            ApiError::BotKicked,
+            ApiError::BotKickedFromSupergroup,
+            ApiError::GroupDeactivated,
+            ApiError::CantInitiateConversation,
+        ];
+
+        // The error, whether it's retried, whether the chat is gone and where it moved to
+        let mut cases: Vec<(RequestError, bool, bool, Option<&str>)> = vec![
+            (RequestError::Network(network), true, false, None),
+            (
+                RequestError::Io(std::io::ErrorKind::TimedOut.into()),
+                true,
+                false,
+                None,
+            ),
+            (
+                RequestError::InvalidJson {
+                    source: json,
+                    raw: "{".into(),
+                },
+                true,
+                false,
+                None,
+            ),
+            (
+                RequestError::RetryAfter(Duration::from_secs(5)),
+                true,
+                false,
+                None,
+            ),
+            (
+                RequestError::MigrateToChatId(-100123),
+                false,
+                false,
+                Some("-100123"),
+            ),
+            (
+                RequestError::Api(ApiError::Unknown("Bad Gateway".to_string())),
+                true,
+                false,
+                None,
+            ),
+            (
+                RequestError::Api(ApiError::Unknown(
+                    "Internal Server Error: restart".to_string(),
+                )),
+                true,
+                false,
+                None,
+            ),
+            (
+                RequestError::Api(ApiError::Unknown("Bad Request: nope".to_string())),
+                false,
+                false,
+                None,
+            ),
+            (
+                RequestError::Api(ApiError::MessageTextIsEmpty),
+                false,
+                false,
+                None,
+            ),
+        ];
+        cases.extend(
+            gone.into_iter()
+                .map(|e| (RequestError::Api(e), false, true, None)),
+        );
+
+        for (error, transient, chat_gone, migrated_to) in cases {
+            let description = format!("{:?}", error);
+            let error = BotError::from(error);
+
+            assert_eq!(error.is_transient(), transient, "{}", description);
+            assert_eq!(error.is_chat_gone(), chat_gone, "{}", description);
+            assert_eq!(
+                error.migrated_to().as_deref(),
+                migrated_to,
+                "{}",
+                description
+            );
+        }
+    }
+
+    #[test]
+    fn bot_error_keeps_the_retry_after_wait() {
+        let wait = Duration::from_secs(7);
+
+        assert_eq!(
+            BotError::from(RequestError::RetryAfter(wait)).retry_after(),
+            Some(wait)
+        );
+        assert_eq!(
+            BotError::from(RequestError::Api(ApiError::BotBlocked)).retry_after(),
+            None
+        );
+    }
+
+    fn digest_message(link: &str, title: &str, category: &str) -> DealMessage {
+        DealMessage {
+            id: link.to_string(),
+            list: "deals".to_string(),
+            feed: DEFAULT_FEED.to_string(),
+            instance: "nl".to_string(),
+            payload: Deal {
+                link: link.to_string(),
+                categories: vec![category.to_string()],
+                title: title.to_string(),
+                price: Some(9.99),
+                original_price: None,
+                temperature: None,
+                description: None,
+                image: None,
+                merchant: None,
+                pub_date: None,
+                guid: None,
+            },
+            alert: None,
+            recipients: None,
+        }
+    }
+
+    #[test]
+    fn format_digest_groups_deals_once() {
+        let messages = vec![
+            digest_message("https://a", "Switch", "gaming"),
+            digest_message("https://b", "Koffie", "boodschappen"),
+            digest_message("https://a", "Switch", "gaming"),
+        ];
+
+        let texts = BotMessageService::format_digest(&messages);
+
+        assert_eq!(texts.len(), 1);
+        assert!(texts[0].starts_with("*2 new deals*\n\n*boodschappen*\n• [Koffie]"));
+        assert!(texts[0].contains("\n\n*gaming*\n• [Switch](https://a) \\- €9,99"));
+    }
+
+    #[test]
+    fn format_digest_splits_long_digests() {
+        let title = "A deal with a title long enough to fill a digest quickly".repeat(2);
+        let messages: Vec<DealMessage> = (0..100)
+            .map(|i| digest_message(&format!("https://deal/{}", i), &title, "gaming"))
+            .collect();
+
+        let texts = BotMessageService::format_digest(&messages);
+
+        assert!(texts.len() > 1);
+        assert!(texts.iter().all(|text| text.chars().count() <= 4000));
+        assert!(texts.iter().skip(1).all(|text| text.starts_with('•')));
+        assert_eq!(
+            texts
+                .iter()
+                .map(|text| text.matches('•').count())
+                .sum::<usize>(),
+            100
+        );
+    }
+
+    #[test]
+    fn append_part_starts_a_new_message_at_the_limit() {
+        let mut texts: Vec<String> = vec![];
+        let mut text = "x".repeat(3990);
+
+        BotMessageService::append_part(&mut texts, &mut text, "\nshort");
+        assert!(texts.is_empty());
+        assert_eq!(text.chars().count(), 3996);
+
+        BotMessageService::append_part(&mut texts, &mut text, "\n\n*gaming*");
+        assert_eq!(texts.len(), 1);
+        assert_eq!(text, "*gaming*");
+    }
+}
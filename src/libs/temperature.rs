@@ -0,0 +1,11 @@
+use crate::libs::variable::get_optional_variable;
+
+// Deals are followed for TEMPERATURE_TRACKING_HOURS after they were queued
+pub fn get_tracking_seconds() -> u64 {
+    get_optional_variable("TEMPERATURE_TRACKING_HOURS", 24u64) * 3600
+}
+
+// Temperatures of tracked deals are checked every TEMPERATURE_INTERVAL_SECONDS
+pub fn get_interval_seconds() -> u64 {
+    get_optional_variable("TEMPERATURE_INTERVAL_SECONDS", 900)
+}
@@ -1,5 +1,6 @@
 use std::{env, process};
-use log::error;
+use std::str::FromStr;
+use log::{error, warn};
 
 pub fn get_environment_variable(key: &str) -> String {
     match env::var(key) {
@@ -10,3 +11,17 @@ pub fn get_environment_variable(key: &str) -> String {
         },
     }
 }
+
+// Settings that can be tuned, an unset or unreadable value falls back to `default`
+pub fn get_optional_variable<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(val) => match val.trim().parse::<T>() {
+            Ok(parsed) => parsed,
+            Err(_e) => {
+                warn!("Invalid environment variable: {}={}", key, val);
+                default
+            },
+        },
+        Err(_e) => default,
+    }
+}
@@ -4,20 +4,23 @@ pub mod structs;
 use libs::variable::get_environment_variable;
 use libs::version::print_version;
 use libs::rss::RSSError;
-use log::{error, info};
+use log::{error, info, warn};
 use rss::Channel;
-use std::thread::sleep;
 use std::time::Duration;
-use structs::message::Message;
-
-use crate::libs::redis::{publish_message, Database};
-use crate::libs::rss::get_rss_data;
+use structs::message::{Deal, Message};
+
+use crate::libs::instance::get_enabled_instances;
+use crate::libs::polling::PollInterval;
+use crate::libs::redis::RedisConnections;
+use crate::libs::rss::{get_feeds, get_rss_data, Feed, FeedResponse};
+use crate::libs::shutdown::Shutdown;
+use crate::libs::temperature::get_tracking_seconds;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 enum QueuingError {
     #[error(transparent)]
-    RedisError(#[from] redis::RedisError),
+    RedisError(#[from] libs::redis::RedisError),
 
     #[error(transparent)]
     RSSError(#[from] RSSError),
@@ -31,56 +34,138 @@ async fn main() -> Result<(), QueuingError> {
     info!("Starting message queuing service");
 
     let redis_url = get_environment_variable("REDIS_URL");
+    let mut shutdown = Shutdown::listen();
+    let tracking_seconds = get_tracking_seconds();
+    let mut poll_interval = PollInterval::from_env();
+    let mut feeds: Vec<Feed> = get_enabled_instances()
+        .into_iter()
+        .flat_map(get_feeds)
+        .collect();
+
+    info!(
+        "Polling feeds: {}",
+        feeds
+            .iter()
+            .map(|feed| format!("{}/{} ({})", feed.instance.name, feed.name, feed.url))
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    match RedisConnections::connect(&redis_url).await {
+        Ok(connections) => {
+            // Deals can only be added once a leftover list has been moved into the stream
+            if let Err(e) = connections.deals.create_message_stream().await {
+                error!("Creating the message stream failed {:?}", e);
+            }
+
+            while !shutdown.is_triggered() {
+                match connections.is_available().await {
+                    true => {
+                        let mut published = 0;
+                        let mut throttled_instances: Vec<&str> = vec![];
+                        let mut retry_after: Option<Duration> = None;
+
+                        for feed in feeds.iter_mut() {
+                            // Everything queued so far is safe in Redis, stop before the next feed
+                            if shutdown.is_triggered() {
+                                break;
+                            }
 
-    match redis::Client::open(redis_url.clone()) {
-        Ok(redis_client) => {
-            loop {
-                match redis_client.get_connection() {
-                    Ok(mut con) => {
-                        // Make the current connection connect to the messages database
-                        let _: Result<(), redis::RedisError> = redis::cmd("SELECT")
-                            .arg(Database::MESSAGE as u8)
-                            .query(&mut con);
-
-                        let mut channel: Channel = get_rss_data().await?;
-                        channel.items.reverse();
-
-                        for item in channel.items {
-                            if let Some(link) = item.link {
-                                let id = link.clone();
-                                let res: i64 = redis::cmd("EXISTS").arg(&id).query(&mut con)?;
-
-                                if res.eq(&1) {
+                            // Don't bother a server that just asked us to slow down
+                            if throttled_instances.contains(&feed.instance.name) {
+                                continue;
+                            }
+
+                            let mut channel: Channel = match get_rss_data(feed).await {
+                                Ok(FeedResponse::Modified(channel)) => *channel,
+                                Ok(FeedResponse::NotModified) => continue,
+                                Ok(FeedResponse::Throttled(wait)) => {
+                                    warn!(
+                                        "Feed {}/{} is throttled, retry after {:?}",
+                                        feed.instance.name, feed.name, wait
+                                    );
+
+                                    throttled_instances.push(feed.instance.name);
+                                    retry_after = retry_after.max(wait);
                                     continue;
                                 }
-
-                                let category = match item.categories.first() {
-                                    Some(c) => c.name.clone(),
-                                    _ => "".to_string(),
-                                };
-
-                                let title = match item.title {
-                                    Some(t) => t,
-                                    _ => "".to_string(),
-                                };
-
-                                let message = Message::new(structs::message::Deal::new(
-                                    link,
-                                    category.to_lowercase(),
-                                    title,
-                                ));
-
-                                if let Err(e) = publish_message(redis_url.clone(), message) {
-                                    error!("Adding to redis failed {:?}", e);
-                                };
+                                Err(e) => {
+                                    error!(
+                                        "Fetching feed {}/{} failed {:?}",
+                                        feed.instance.name, feed.name, e
+                                    );
+                                    continue;
+                                }
+                            };
+                            channel.items.reverse();
+
+                            for item in channel.items {
+                                if let Some(deal) = Deal::from_item(&item) {
+                                    let message = Message::new(deal, feed);
+
+                                    // The connection reconnects by itself, the deal is tried
+                                    // again when the feed changes
+                                    match connections.deals.is_known(&message.id).await {
+                                        Ok(false) => (),
+                                        Ok(true) => continue,
+                                        Err(e) => {
+                                            error!("Checking {} failed {:?}", message.id, e);
+                                            continue;
+                                        }
+                                    }
+
+                                    // Pepper adds and renames categories, keep track of the
+                                    // ones actually in use
+                                    let categories: Vec<String> = item
+                                        .categories
+                                        .iter()
+                                        .map(|c| c.name.trim().to_string())
+                                        .filter(|c| !c.is_empty())
+                                        .collect();
+
+                                    if let Err(e) = connections
+                                        .deals
+                                        .record_categories(
+                                            &message.instance,
+                                            &message.payload.link,
+                                            &categories,
+                                        )
+                                        .await
+                                    {
+                                        error!("Recording categories failed {:?}", e);
+                                    }
+
+                                    if let Err(e) = connections
+                                        .deals
+                                        .track_deal(&message, tracking_seconds)
+                                        .await
+                                    {
+                                        error!("Tracking deal failed {:?}", e);
+                                    }
+
+                                    match connections.deals.publish_message(message).await {
+                                        Ok(_) => published += 1,
+                                        Err(e) => error!("Adding to redis failed {:?}", e),
+                                    };
+                                }
                             }
                         }
 
-                        sleep(Duration::from_secs(300));
+                        if !throttled_instances.is_empty() {
+                            poll_interval.throttled(retry_after);
+                        } else if published > 0 {
+                            poll_interval.found_new();
+                        } else {
+                            poll_interval.found_nothing();
+                        }
                     }
-                    Err(_) => error!("Redis connection failed"),
+                    false => error!("Redis connection failed"),
                 };
+
+                shutdown.sleep(poll_interval.next()).await;
             }
+
+            info!("Stopped message queuing service");
         }
         _ => error!("Couldn't connect to redis"),
     };
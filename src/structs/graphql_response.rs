@@ -33,3 +33,30 @@ pub struct Deal {
     pub percentage: f64,
     pub temperature: f64,
 }
+
+// The results of a /deals search, kept so its pages and sort orders show the same deals
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DealSearch {
+    pub instance: String,
+    pub search: String,
+    pub suggestions: Suggestions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadResponse {
+    pub data: ThreadData,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadData {
+    pub thread: Thread,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Thread {
+    pub thread_id: String,
+    pub temperature: f64,
+}
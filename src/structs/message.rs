@@ -1,6 +1,13 @@
-use serde::{Deserialize, Serialize};
+use rss::Item;
+use serde::{Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
+use crate::libs::instance::DEFAULT_INSTANCE;
+use crate::libs::rss::{
+    get_item_description, get_item_image, get_item_prices, get_pepper_attribute, Feed, DEFAULT_FEED,
+};
+
+// Key of the Redis stream the deals are queued on
 pub static LIST_NAME: &str = "deals";
 
 #[derive(Debug, Error)]
@@ -12,36 +19,220 @@ pub enum MessageError {
     ParseError,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub id: String,
     pub list: String,
+    #[serde(default = "default_feed")]
+    pub feed: String,
+    #[serde(default = "default_instance")]
+    pub instance: String,
     pub payload: Deal,
+    #[serde(default)]
+    pub alert: Option<TemperatureAlert>,
+    // Set when a shutdown interrupted the fan-out, holds the chats still waiting for it
+    #[serde(default)]
+    pub recipients: Option<Vec<String>>,
+}
+
+// A message that kept failing to reach a chat, kept around to inspect and replay it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub chat_id: String,
+    pub message: Message,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemperatureAlert {
+    pub previous: f64,
+    pub current: f64,
+}
+
+fn default_feed() -> String {
+    DEFAULT_FEED.to_string()
+}
+
+fn default_instance() -> String {
+    DEFAULT_INSTANCE.to_string()
 }
 
 impl Message {
-    pub fn new(payload: Deal) -> Message {
+    pub fn new(payload: Deal, feed: &Feed) -> Message {
+        // Deals from the default feed keep the plain link as id, so the
+        // deduplication keys that are already stored stay valid. Links already
+        // contain the domain, which keeps the ids of different instances apart
+        let id = match feed.name.eq(DEFAULT_FEED) {
+            true => payload.link.clone(),
+            false => format!("{}:{}", feed.name, payload.link),
+        };
+
+        Message {
+            id,
+            list: String::from(LIST_NAME),
+            feed: feed.name.clone(),
+            instance: feed.instance.name.to_string(),
+            payload,
+            alert: None,
+            recipients: None,
+        }
+    }
+
+    // Re-queues a deal that got hotter, every update gets its own id to pass deduplication
+    pub fn temperature_alert(message: &Message, previous: f64, current: f64) -> Message {
+        let mut payload = message.payload.clone();
+        payload.temperature = Some(current);
+
         Message {
-            id: payload.link.clone(),
+            id: format!("temperature:{}:{}", current, payload.link),
             list: String::from(LIST_NAME),
+            feed: message.feed.clone(),
+            instance: message.instance.clone(),
             payload,
+            alert: Some(TemperatureAlert { previous, current }),
+            recipients: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Deal {
     pub link: String,
-    pub category: String,
+    // Lowercase and in the order of the feed, the first one is shown with the deal
+    #[serde(alias = "category", deserialize_with = "deserialize_categories")]
+    pub categories: Vec<String>,
     pub title: String,
+    #[serde(default)]
+    pub price: Option<f64>,
+    #[serde(default)]
+    pub original_price: Option<f64>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub merchant: Option<String>,
+    #[serde(default)]
+    pub pub_date: Option<String>,
+    #[serde(default)]
+    pub guid: Option<String>,
+}
+
+// Deals used to carry a single category, messages queued before still have that form
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredCategories {
+    Single(String),
+    List(Vec<String>),
+}
+
+fn deserialize_categories<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match StoredCategories::deserialize(deserializer)? {
+        StoredCategories::Single(category) if category.is_empty() => vec![],
+        StoredCategories::Single(category) => vec![category],
+        StoredCategories::List(categories) => categories,
+    })
 }
 
 impl Deal {
-    pub fn new(link: String, category: String, title: String) -> Deal {
-        Deal {
-            link,
-            category,
-            title,
+    // Items without a link can't be sent to anyone, so those are skipped
+    pub fn from_item(item: &Item) -> Option<Deal> {
+        let link = item.link.clone()?;
+
+        let mut categories: Vec<String> = vec![];
+        for category in &item.categories {
+            let category = category.name.trim().to_lowercase();
+
+            if !category.is_empty() && !categories.contains(&category) {
+                categories.push(category);
+            }
         }
+
+        let (price, original_price) = get_item_prices(item);
+
+        Some(Deal {
+            link,
+            categories,
+            title: item.title.clone().unwrap_or_default(),
+            price,
+            original_price,
+            temperature: None,
+            description: get_item_description(item),
+            image: get_item_image(item),
+            merchant: get_pepper_attribute(item, "name"),
+            pub_date: item.pub_date.clone(),
+            guid: item.guid.as_ref().map(|guid| guid.value.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_deal(json: &str) -> Deal {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn deal_reads_legacy_single_category() {
+        let deal = parse_deal(r#"{"link":"https://a","title":"A","category":"gaming"}"#);
+        assert_eq!(deal.categories, vec!["gaming"]);
+
+        let deal = parse_deal(r#"{"link":"https://a","title":"A","category":""}"#);
+        assert!(deal.categories.is_empty());
+
+        let deal = parse_deal(r#"{"link":"https://a","title":"A","categories":["gaming","pc"]}"#);
+        assert_eq!(deal.categories, vec!["gaming", "pc"]);
+    }
+
+    #[test]
+    fn deal_defaults_missing_fields() {
+        let deal = parse_deal(r#"{"link":"https://a","title":"A","category":"gaming"}"#);
+
+        assert_eq!(deal.price, None);
+        assert_eq!(deal.original_price, None);
+        assert_eq!(deal.temperature, None);
+        assert_eq!(deal.description, None);
+        assert_eq!(deal.image, None);
+        assert_eq!(deal.merchant, None);
+        assert_eq!(deal.pub_date, None);
+        assert_eq!(deal.guid, None);
+    }
+
+    #[test]
+    fn message_defaults_missing_fields() {
+        let message: Message = serde_json::from_str(
+            r#"{"id":"https://a","list":"deals","payload":{"link":"https://a","title":"A","category":"gaming"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(message.feed, DEFAULT_FEED);
+        assert_eq!(message.instance, DEFAULT_INSTANCE);
+        assert!(message.alert.is_none());
+        assert!(message.recipients.is_none());
+    }
+
+    #[test]
+    fn deal_round_trips() {
+        let deal = parse_deal(
+            r#"{"link":"https://a","title":"A","categories":["gaming"],"price":9.99,"original_price":19.99,"temperature":120.5,"description":"D","image":"https://i","merchant":"M","pub_date":"Mon, 1 Jan 2024 00:00:00 +0000","guid":"1"}"#,
+        );
+        let stored = parse_deal(&serde_json::to_string(&deal).unwrap());
+
+        assert_eq!(stored.categories, vec!["gaming"]);
+        assert_eq!(stored.price, Some(9.99));
+        assert_eq!(stored.original_price, Some(19.99));
+        assert_eq!(stored.temperature, Some(120.5));
+        assert_eq!(stored.description.as_deref(), Some("D"));
+        assert_eq!(stored.image.as_deref(), Some("https://i"));
+        assert_eq!(stored.merchant.as_deref(), Some("M"));
+        assert_eq!(stored.guid.as_deref(), Some("1"));
     }
 }
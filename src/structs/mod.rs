@@ -1,2 +1,3 @@
 pub mod graphql_response;
 pub mod message;
+pub mod subscriber;
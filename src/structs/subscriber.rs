@@ -0,0 +1,312 @@
+use chrono_tz::Tz;
+use std::collections::HashMap;
+
+use crate::libs::digest::Digest;
+use crate::libs::instance::DEFAULT_INSTANCE;
+use crate::libs::quiet::{parse_timezone, QuietHours, DEFAULT_TIMEZONE};
+use crate::libs::redis::{get_timestamp, split_preference, Preference};
+use crate::libs::rss::DEFAULT_FEED;
+use crate::structs::message::{Deal, Message, TemperatureAlert};
+
+// How deals reach the subscriber, right away or bundled in a digest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    #[default]
+    Instant,
+    Digest(Digest),
+}
+
+impl Mode {
+    pub fn value(&self) -> &str {
+        match self {
+            Mode::Instant => "instant",
+            Mode::Digest(digest) => digest.value(),
+        }
+    }
+
+    pub fn from_value(value: &str) -> Option<Mode> {
+        match value.trim().to_lowercase().as_str() {
+            "instant" => Some(Mode::Instant),
+            _ => Digest::from_value(value).map(Mode::Digest),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Subscriber {
+    // None follows all categories
+    pub categories: Option<Vec<String>>,
+    pub keywords: Vec<String>,
+    pub language: Option<String>,
+    pub created_at: u64,
+    pub mode: Mode,
+    pub paused_until: Option<u64>,
+    pub max_price: Option<f64>,
+    pub feeds: Vec<String>,
+    pub instance: Option<String>,
+    pub hot_threshold: Option<f64>,
+    pub excluded_categories: Vec<String>,
+    pub quiet_hours: Option<QuietHours>,
+    pub timezone: Option<Tz>,
+}
+
+impl Subscriber {
+    // Reads a record from the subscriber database, records without created_at belong to
+    // chats that set preferences but never subscribed
+    pub fn from_record(mut record: HashMap<String, String>) -> Option<Subscriber> {
+        let created_at = record
+            .remove(Preference::CreatedAt.value())
+            .and_then(|c| c.parse::<u64>().ok())?;
+
+        let categories = split_preference(record.remove(Preference::Categories.value()));
+
+        Some(Subscriber {
+            categories: match categories.is_empty() {
+                true => None,
+                false => Some(categories),
+            },
+            keywords: split_preference(record.remove(Preference::Keywords.value())),
+            language: record.remove(Preference::Language.value()),
+            created_at,
+            mode: record
+                .remove(Preference::Mode.value())
+                .and_then(|m| Mode::from_value(&m))
+                .unwrap_or_default(),
+            paused_until: record
+                .remove(Preference::PausedUntil.value())
+                .and_then(|p| p.parse::<u64>().ok()),
+            max_price: record
+                .remove(Preference::MaxPrice.value())
+                .and_then(|p| p.parse::<f64>().ok()),
+            feeds: split_preference(record.remove(Preference::Feeds.value())),
+            instance: record.remove(Preference::Instance.value()),
+            hot_threshold: record
+                .remove(Preference::HotThreshold.value())
+                .and_then(|t| t.parse::<f64>().ok()),
+            excluded_categories: split_preference(
+                record.remove(Preference::ExcludedCategories.value()),
+            ),
+            quiet_hours: record
+                .remove(Preference::QuietHours.value())
+                .and_then(|q| QuietHours::from_value(&q)),
+            timezone: record
+                .remove(Preference::Timezone.value())
+                .and_then(|t| parse_timezone(&t)),
+        })
+    }
+
+    pub fn is_paused(&self, timestamp: u64) -> bool {
+        self.paused_until.is_some_and(|until| until > timestamp)
+    }
+
+    // Only the update that crosses the threshold results in an alert
+    pub fn matches_alert(&self, alert: &TemperatureAlert) -> bool {
+        match self.hot_threshold {
+            Some(threshold) => alert.previous < threshold && alert.current >= threshold,
+            None => false,
+        }
+    }
+
+    pub fn matches_instance(&self, instance: &str) -> bool {
+        match &self.instance {
+            Some(i) => i.eq(instance),
+            None => instance.eq(DEFAULT_INSTANCE),
+        }
+    }
+
+    pub fn matches_feed(&self, feed: &str) -> bool {
+        match self.feeds.is_empty() {
+            true => feed.eq(DEFAULT_FEED),
+            false => self.feeds.iter().any(|f| f.eq(feed)),
+        }
+    }
+
+    // Deals without a known price are never held back by the price filter
+    pub fn matches_price(&self, deal: &Deal) -> bool {
+        match (self.max_price, deal.price) {
+            (Some(max_price), Some(price)) => price <= max_price,
+            _ => true,
+        }
+    }
+
+    pub fn matches_category(&self, deal: &Deal) -> bool {
+        match &self.categories {
+            Some(categories) => deal.categories.iter().any(|c| categories.contains(c)),
+            None => false,
+        }
+    }
+
+    pub fn excludes_category(&self, deal: &Deal) -> bool {
+        deal.categories
+            .iter()
+            .any(|c| self.excluded_categories.contains(c))
+    }
+
+    pub fn matches_keyword(&self, deal: &Deal) -> bool {
+        let title = deal.title.to_lowercase();
+
+        self.keywords
+            .iter()
+            .any(|keyword| title.contains(keyword.as_str()))
+    }
+
+    // A subscriber without any filters receives every affordable deal of their instance
+    // and feeds, otherwise the deal has to match one of the category filters or keywords.
+    // Temperature alerts replace the feed check with the subscriber's hot threshold.
+    // Excluded categories are never sent, paused subscribers receive nothing at all
+    pub fn wants(&self, message: &Message) -> bool {
+        let deal = &message.payload;
+
+        if self.is_paused(get_timestamp()) {
+            return false;
+        }
+
+        let matches_source = match &message.alert {
+            Some(alert) => self.matches_alert(alert),
+            None => self.matches_feed(&message.feed),
+        };
+
+        if !matches_source
+            || !self.matches_instance(&message.instance)
+            || !self.matches_price(deal)
+            || self.excludes_category(deal)
+        {
+            return false;
+        }
+
+        if self.categories.is_none() && self.keywords.is_empty() {
+            return true;
+        }
+
+        self.matches_category(deal) || self.matches_keyword(deal)
+    }
+
+    // When a deal for this subscriber should be delivered, None to send it right away.
+    // Digests go out at their interval, quiet hours hold deals until they end
+    pub fn delivery_due(&self, timestamp: u64) -> Option<u64> {
+        let timezone = self.timezone.unwrap_or(DEFAULT_TIMEZONE);
+
+        let due = match self.mode {
+            Mode::Digest(digest) => digest.next_due(timestamp, timezone),
+            Mode::Instant => timestamp,
+        };
+
+        let held_until = self
+            .quiet_hours
+            .and_then(|quiet_hours| quiet_hours.held_until(due, timezone));
+
+        match (held_until, self.mode) {
+            (Some(until), _) => Some(until),
+            (None, Mode::Digest(_)) => Some(due),
+            (None, Mode::Instant) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn message(categories: &[&str]) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": "https://a",
+            "list": "deals",
+            "payload": {
+                "link": "https://a",
+                "title": "Nintendo Switch OLED",
+                "categories": categories,
+                "price": 299.0,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn subscriber() -> Subscriber {
+        Subscriber {
+            created_at: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn wants_skips_excluded_categories() {
+        let subscriber = Subscriber {
+            excluded_categories: vec!["gaming".to_string()],
+            ..subscriber()
+        };
+
+        assert!(subscriber.wants(&message(&["elektronica"])));
+        assert!(!subscriber.wants(&message(&["gaming"])));
+
+        // Exclusions win over a followed category or a watched keyword
+        let subscriber = Subscriber {
+            categories: Some(vec!["elektronica".to_string()]),
+            keywords: vec!["switch".to_string()],
+            ..subscriber
+        };
+        assert!(!subscriber.wants(&message(&["elektronica", "gaming"])));
+    }
+
+    #[test]
+    fn wants_matches_any_category_of_a_deal() {
+        let subscriber = Subscriber {
+            categories: Some(vec!["gaming".to_string()]),
+            ..subscriber()
+        };
+
+        assert!(subscriber.wants(&message(&["elektronica", "gaming"])));
+        assert!(!subscriber.wants(&message(&["elektronica", "home & living"])));
+        assert!(!subscriber.wants(&message(&[])));
+
+        // A deal without categories can still be found by keyword
+        let subscriber = Subscriber {
+            keywords: vec!["switch".to_string()],
+            ..subscriber
+        };
+        assert!(subscriber.wants(&message(&[])));
+    }
+
+    #[test]
+    fn wants_without_filters_follows_price_and_feed() {
+        let mut deal = message(&["gaming"]);
+        assert!(subscriber().wants(&deal));
+
+        let subscriber = Subscriber {
+            max_price: Some(250.0),
+            ..subscriber()
+        };
+        assert!(!subscriber.wants(&deal));
+
+        deal.feed = "hot".to_string();
+        assert!(!Subscriber::default().wants(&deal));
+    }
+
+    #[test]
+    fn delivery_due_holds_deals_in_quiet_hours() {
+        let tz = DEFAULT_TIMEZONE;
+        let night = tz
+            .with_ymd_and_hms(2024, 1, 15, 23, 30, 0)
+            .unwrap()
+            .timestamp() as u64;
+        let morning = tz
+            .with_ymd_and_hms(2024, 1, 16, 7, 0, 0)
+            .unwrap()
+            .timestamp() as u64;
+
+        assert_eq!(subscriber().delivery_due(night), None);
+
+        let subscriber = Subscriber {
+            quiet_hours: QuietHours::from_value("23:00-07:00"),
+            ..subscriber()
+        };
+        assert_eq!(subscriber.delivery_due(night), Some(morning));
+        assert_eq!(subscriber.delivery_due(morning), None);
+
+        let subscriber = Subscriber {
+            mode: Mode::Digest(Digest::Hourly),
+            ..subscriber
+        };
+        assert_eq!(subscriber.delivery_due(morning), Some(morning + 3600));
+    }
+}
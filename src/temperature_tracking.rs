@@ -0,0 +1,123 @@
+pub mod libs;
+pub mod structs;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libs::instance::get_instance_or_default;
+use libs::pepper_request::{get_thread_id, PepperRequest};
+use libs::redis::RedisConnections;
+use libs::shutdown::Shutdown;
+use libs::temperature::{get_interval_seconds, get_tracking_seconds};
+use libs::variable::get_environment_variable;
+use libs::version::print_version;
+use log::{error, info, warn};
+use structs::message::Message;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    print_version();
+
+    info!("Starting temperature tracking service");
+
+    let redis_url = get_environment_variable("REDIS_URL");
+    let mut shutdown = Shutdown::listen();
+    let tracking_seconds = get_tracking_seconds();
+    let interval_seconds = get_interval_seconds();
+
+    match RedisConnections::connect(&redis_url).await {
+        Ok(connections) => {
+            while !shutdown.is_triggered() {
+                match connections.is_available().await {
+                    true => {
+                        // Only thresholds that subscribers actually set are worth an alert
+                        let thresholds: Vec<f64> = match connections.subscribers.get_all().await {
+                            Ok(subs) => subs.values().filter_map(|s| s.hot_threshold).collect(),
+                            Err(_) => vec![],
+                        };
+
+                        let deals = connections.deals.get_tracked_deals(tracking_seconds).await;
+                        info!("Checking temperature of {} deals", deals.len());
+
+                        let mut cookie_headers: HashMap<String, Vec<String>> = HashMap::new();
+
+                        for message in deals {
+                            if shutdown.is_triggered() {
+                                break;
+                            }
+
+                            let instance = get_instance_or_default(Some(&message.instance));
+                            let pepper_request = PepperRequest::new(instance);
+
+                            let thread_id = match get_thread_id(&message.payload.link) {
+                                Some(thread_id) => thread_id,
+                                None => continue,
+                            };
+
+                            // Cookies are requested once per instance for every round
+                            if !cookie_headers.contains_key(instance.name) {
+                                match pepper_request.get_cookie_headers().await {
+                                    Some(headers) => {
+                                        cookie_headers.insert(instance.name.to_string(), headers);
+                                    }
+                                    None => continue,
+                                }
+                            }
+
+                            let thread = pepper_request
+                                .thread(&thread_id, cookie_headers[instance.name].clone())
+                                .await;
+
+                            let current = match thread {
+                                Some(t) => t.data.thread.temperature,
+                                None => {
+                                    warn!("No temperature found for {}", message.payload.link);
+                                    continue;
+                                }
+                            };
+
+                            // The first measurement compares against the temperature the deal
+                            // was queued with, so deals that are hot right away alert as well
+                            let previous = match connections
+                                .deals
+                                .add_temperature(&message.payload.link, current, tracking_seconds)
+                                .await
+                            {
+                                Ok(previous) => {
+                                    previous.or(message.payload.temperature).unwrap_or(0.0)
+                                }
+                                Err(e) => {
+                                    error!("Storing temperature failed {:?}", e);
+                                    continue;
+                                }
+                            };
+
+                            let crossed = thresholds
+                                .iter()
+                                .any(|threshold| previous < *threshold && current >= *threshold);
+
+                            if crossed {
+                                info!(
+                                    "{} went from {} to {} degrees",
+                                    message.payload.link, previous, current
+                                );
+
+                                let alert = Message::temperature_alert(&message, previous, current);
+                                if let Err(e) = connections.deals.publish_message(alert).await {
+                                    error!("Adding to redis failed {:?}", e);
+                                }
+                            }
+                        }
+                    }
+                    false => error!("Redis connection failed"),
+                }
+
+                shutdown.sleep(Duration::from_secs(interval_seconds)).await;
+            }
+
+            info!("Stopped temperature tracking service");
+        }
+        Err(_) => error!("Couldn't connect to redis"),
+    }
+}
@@ -12,7 +12,8 @@ use axum::{
 };
 use include_dir::{include_dir, Dir};
 use libs::middleware::request_logger;
-use libs::redis::{get_config, get_subscriber_amount};
+use libs::redis::RedisConnections;
+use libs::shutdown::Shutdown;
 use libs::variable::get_environment_variable;
 use libs::version::print_version;
 use log::{error, info};
@@ -27,15 +28,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("Starting webserver service");
 
     let redis_url = get_environment_variable("REDIS_URL");
+    let mut shutdown = Shutdown::listen();
 
-    match redis::Client::open(redis_url.clone()) {
-        Ok(redis_client) => {
+    match RedisConnections::connect(&redis_url).await {
+        Ok(connections) => {
             let app = axum::Router::new()
                 .route("/", get(render_index))
                 .route("/index.html", get(render_index))
                 .route("/_health", get(health))
                 .route("/*path", get(static_path))
-                .with_state(redis_client)
+                .with_state(connections)
                 .layer(from_fn(request_logger));
 
             let addr = std::net::SocketAddr::from(([0, 0, 0, 0], 8080));
@@ -43,7 +45,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             axum::Server::bind(&addr)
                 .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(async move { shutdown.wait().await })
                 .await?;
+
+            info!("Stopped webserver service");
         }
         Err(_) => error!("Redis connection failed"),
     }
@@ -51,7 +56,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn render_index(State(redis_service): State<redis::Client>) -> impl IntoResponse {
+async fn render_index(State(redis_service): State<RedisConnections>) -> impl IntoResponse {
     match STATIC_DIR.get_file("index.html") {
         None => Response::builder()
             .status(axum::http::status::StatusCode::NOT_FOUND)
@@ -73,7 +78,7 @@ async fn render_index(State(redis_service): State<redis::Client>) -> impl IntoRe
     }
 }
 
-async fn health(State(_redis_service): State<redis::Client>) -> impl IntoResponse {
+async fn health(State(_redis_service): State<RedisConnections>) -> impl IntoResponse {
     Response::builder()
         .status(axum::http::status::StatusCode::OK)
         .header(
@@ -108,23 +113,21 @@ async fn static_path(Path(path): Path<String>) -> impl IntoResponse {
     }
 }
 
-async fn set_template_values(contents: Option<&str>, redis_client: redis::Client) -> String {
-    match redis_client.get_connection() {
-        Ok(mut con) => {
-            let subscriber_count = get_subscriber_amount(&mut con).await;
-            let message_count: String = get_config(
-                &mut con,
-                libs::redis::Config::MessagesSentKey,
-                libs::redis::Database::CONFIG,
-            )
-            .unwrap_or("1337".to_string());
-
-            let deals_count: String = get_config(
-                &mut con,
-                libs::redis::Config::DealsSentKey,
-                libs::redis::Database::CONFIG,
-            )
-            .unwrap_or("1337".to_string());
+async fn set_template_values(contents: Option<&str>, connections: RedisConnections) -> String {
+    match connections.is_available().await {
+        true => {
+            let subscriber_count = connections.subscribers.get_amount().await;
+            let message_count: String = connections
+                .config
+                .get(libs::redis::Config::MessagesSentKey)
+                .await
+                .unwrap_or("1337".to_string());
+
+            let deals_count: String = connections
+                .config
+                .get(libs::redis::Config::DealsSentKey)
+                .await
+                .unwrap_or("1337".to_string());
 
             let template = contents.unwrap_or("");
             template
@@ -135,6 +138,6 @@ async fn set_template_values(contents: Option<&str>, redis_client: redis::Client
                 .replace("__MESSAGES_SENT__", &message_count)
                 .replace("__DEALS_SENT__", &deals_count)
         }
-        _ => contents.unwrap_or("").to_string(),
+        false => contents.unwrap_or("").to_string(),
     }
 }